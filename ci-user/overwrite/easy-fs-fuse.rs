@@ -74,6 +74,8 @@ fn easy_fs_pack() -> std::io::Result<()> {
         // write data to easy-fs
         inode.write_at(0, all_data.as_slice());
     }
+    // write everything back before the image is used
+    efs.lock().sync();
     // list apps
     for app in root_inode.ls() {
         println!("{}", app);
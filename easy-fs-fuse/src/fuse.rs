@@ -0,0 +1,419 @@
+//! A FUSE daemon serving an easy-fs image on the host
+
+use easy_fs::{EasyFileSystem, Inode, Metadata, BLOCK_SZ, MAX_FILE_SIZE, NAME_LENGTH_LIMIT};
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
+};
+use libc::{EEXIST, EFBIG, EINVAL, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY};
+use spin::Mutex;
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long the kernel may cache entries and attributes,
+/// the image is not changed behind the daemon's back
+const TTL: Duration = Duration::from_secs(1);
+
+/// FUSE numbers the root 1 while easy-fs numbers it 0
+fn to_ino(inode_id: u32) -> u64 {
+    inode_id as u64 + 1
+}
+
+/// Get the time a number of seconds since the epoch
+fn to_time(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Get the number of seconds since the epoch of a time
+fn to_secs(time: TimeOrNow) -> u64 {
+    let time = match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    };
+    time.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+/// Give a new inode the mode it was created with and the caller as its owner
+fn init_metadata(inode: &Inode, req: &Request, mode: u32, umask: u32) {
+    inode.set_metadata(Metadata {
+        perm: (mode & !umask & 0o7777) as u16,
+        uid: req.uid(),
+        gid: req.gid(),
+        ..inode.metadata()
+    });
+}
+
+/// Serve an easy-fs through FUSE, mapping FUSE inode numbers to easy-fs ones.
+/// Every open file and directory holds a handle on its inode,
+/// so that it stays readable after it is unlinked or replaced until it is released.
+pub struct EasyFuse {
+    efs: Arc<Mutex<EasyFileSystem>>,
+}
+
+impl EasyFuse {
+    /// Serve the filesystem
+    pub fn new(efs: Arc<Mutex<EasyFileSystem>>) -> Self {
+        Self { efs }
+    }
+    /// Get the inode of a FUSE inode number
+    fn inode(&self, ino: u64) -> Option<Inode> {
+        if ino == 0 || ino > u32::MAX as u64 {
+            return None;
+        }
+        EasyFileSystem::get_inode(&self.efs, (ino - 1) as u32)
+    }
+    /// Get a directory and check a name to be looked up or created in it
+    fn dir_and_name<'a>(&self, parent: u64, name: &'a OsStr) -> Result<(Inode, &'a str), i32> {
+        let dir = self.inode(parent).ok_or(ENOENT)?;
+        if !dir.is_dir() {
+            return Err(ENOTDIR);
+        }
+        let name = name.to_str().ok_or(EINVAL)?;
+        if name.len() > NAME_LENGTH_LIMIT {
+            return Err(ENAMETOOLONG);
+        }
+        Ok((dir, name))
+    }
+    /// Build the FUSE attributes of an inode
+    fn attr(&self, inode: &Inode) -> FileAttr {
+        let size = inode.size() as u64;
+        let metadata = inode.metadata();
+        let kind = if inode.is_dir() {
+            FileType::Directory
+        } else {
+            FileType::RegularFile
+        };
+        FileAttr {
+            ino: to_ino(inode.inode_id()),
+            size,
+            blocks: (size + 511) / 512,
+            atime: to_time(metadata.atime),
+            mtime: to_time(metadata.mtime),
+            ctime: to_time(metadata.ctime),
+            // easy-fs does not record the creation time
+            crtime: to_time(metadata.ctime),
+            kind,
+            perm: metadata.perm,
+            nlink: inode.nlink(),
+            uid: metadata.uid,
+            gid: metadata.gid,
+            rdev: 0,
+            blksize: BLOCK_SZ as u32,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for EasyFuse {
+    fn destroy(&mut self) {
+        self.efs.lock().sync();
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let (dir, name) = match self.dir_and_name(parent, name) {
+            Ok(pair) => pair,
+            Err(errno) => return reply.error(errno),
+        };
+        match dir.find(name) {
+            Some(inode) => reply.entry(&TTL, &self.attr(&inode), 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.inode(ino) {
+            Some(inode) => reply.attr(&TTL, &self.attr(&inode)),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let inode = match self.inode(ino) {
+            Some(inode) => inode,
+            None => return reply.error(ENOENT),
+        };
+        if let Some(size) = size {
+            if size > MAX_FILE_SIZE as u64 {
+                return reply.error(EFBIG);
+            }
+            if !inode.truncate(size as u32) {
+                return reply.error(EISDIR);
+            }
+        }
+        if mode.is_some() || uid.is_some() || gid.is_some() || atime.is_some() || mtime.is_some() {
+            let metadata = inode.metadata();
+            inode.set_metadata(Metadata {
+                perm: mode.map_or(metadata.perm, |mode| (mode & 0o7777) as u16),
+                uid: uid.unwrap_or(metadata.uid),
+                gid: gid.unwrap_or(metadata.gid),
+                atime: atime.map_or(metadata.atime, to_secs),
+                mtime: mtime.map_or(metadata.mtime, to_secs),
+                ..metadata
+            });
+        }
+        reply.attr(&TTL, &self.attr(&inode));
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let (dir, name) = match self.dir_and_name(parent, name) {
+            Ok(pair) => pair,
+            Err(errno) => return reply.error(errno),
+        };
+        if dir.find(name).is_some() {
+            return reply.error(EEXIST);
+        }
+        match dir.mkdir(name) {
+            Some(inode) => {
+                init_metadata(&inode, req, mode, umask);
+                reply.entry(&TTL, &self.attr(&inode), 0)
+            }
+            None => reply.error(ENOSPC),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (dir, name) = match self.dir_and_name(parent, name) {
+            Ok(pair) => pair,
+            Err(errno) => return reply.error(errno),
+        };
+        match dir.find(name) {
+            None => reply.error(ENOENT),
+            Some(inode) if inode.is_dir() => reply.error(EISDIR),
+            Some(_) => {
+                dir.unlink(name);
+                reply.ok();
+            }
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (dir, name) = match self.dir_and_name(parent, name) {
+            Ok(pair) => pair,
+            Err(errno) => return reply.error(errno),
+        };
+        match dir.find(name) {
+            None => reply.error(ENOENT),
+            Some(inode) if !inode.is_dir() => reply.error(ENOTDIR),
+            Some(_) if dir.rmdir(name) => reply.ok(),
+            // "." and ".." end up here too
+            Some(_) => reply.error(ENOTEMPTY),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        // neither RENAME_NOREPLACE nor RENAME_EXCHANGE is supported
+        if flags != 0 {
+            return reply.error(EINVAL);
+        }
+        let ((dir, name), (new_dir, new_name)) =
+            match (self.dir_and_name(parent, name), self.dir_and_name(newparent, newname)) {
+                (Ok(old), Ok(new)) => (old, new),
+                (Err(errno), _) | (_, Err(errno)) => return reply.error(errno),
+            };
+        let inode = match dir.find(name) {
+            Some(inode) => inode,
+            None => return reply.error(ENOENT),
+        };
+        if dir.rename(name, &new_dir, new_name) {
+            return reply.ok();
+        }
+        // tell why the move is refused
+        let errno = match new_dir.find(new_name) {
+            Some(target) if target.is_dir() && !inode.is_dir() => EISDIR,
+            Some(target) if !target.is_dir() && inode.is_dir() => ENOTDIR,
+            Some(target) if target.is_dir() && !target.ls().is_empty() => ENOTEMPTY,
+            // "." and "..", or a directory moved under itself
+            _ => EINVAL,
+        };
+        reply.error(errno);
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if offset < 0 {
+            return reply.error(EINVAL);
+        }
+        let inode = match self.inode(ino) {
+            Some(inode) => inode,
+            None => return reply.error(ENOENT),
+        };
+        let mut buf = vec![0u8; size as usize];
+        let len = inode.read_at(offset as usize, &mut buf);
+        reply.data(&buf[..len]);
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        if offset < 0 {
+            return reply.error(EINVAL);
+        }
+        if offset as u64 + data.len() as u64 > MAX_FILE_SIZE as u64 {
+            return reply.error(EFBIG);
+        }
+        match self.inode(ino) {
+            Some(inode) if inode.is_dir() => reply.error(EISDIR),
+            Some(inode) => match inode.write_at(offset as usize, data) {
+                // nothing could be written for want of blocks
+                0 if !data.is_empty() => reply.error(ENOSPC),
+                written => reply.written(written as u32),
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.inode(ino) {
+            Some(inode) => {
+                inode.open_handle();
+                reply.opened(0, 0);
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        // an orphan is still in use until this, so its inode is found
+        if let Some(inode) = self.inode(ino) {
+            inode.close_handle();
+        }
+        reply.ok();
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.open(req, ino, flags, reply);
+    }
+
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        self.release(req, ino, fh, flags, None, false, reply);
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.inode(ino) {
+            Some(inode) => {
+                inode.fsync();
+                reply.ok();
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let dir = match self.inode(ino) {
+            Some(dir) if dir.is_dir() => dir,
+            Some(_) => return reply.error(ENOTDIR),
+            None => return reply.error(ENOENT),
+        };
+        let mut offset = offset as usize;
+        while let Some((dirent, next)) = dir.read_dir(offset) {
+            let kind = if dirent.is_dir {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            };
+            // the offset passed back to us is that of the next entry
+            if reply.add(to_ino(dirent.inode_id), next as i64, kind, &dirent.name) {
+                break;
+            }
+            offset = next;
+        }
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let (dir, name) = match self.dir_and_name(parent, name) {
+            Ok(pair) => pair,
+            Err(errno) => return reply.error(errno),
+        };
+        if dir.find(name).is_some() {
+            return reply.error(EEXIST);
+        }
+        match dir.create(name) {
+            Some(inode) => {
+                init_metadata(&inode, req, mode, umask);
+                // the new file is opened as well, and released like any other
+                inode.open_handle();
+                reply.created(&TTL, &self.attr(&inode), 0, 0, 0)
+            }
+            None => reply.error(ENOSPC),
+        }
+    }
+}
@@ -1,13 +1,17 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
-use std::fs::{read_dir, File, OpenOptions};
+#[cfg(feature = "fuse")]
+mod fuse;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use easy_fs::{BlockDevice, EasyFileSystem, Inode, Metadata, NAME_LENGTH_LIMIT};
+use std::fs::{create_dir_all, read_dir, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Use a block size of 512 bytes
 const BLOCK_SZ: usize = 512;
-const BLOCK_NUM: usize = 131072; //64*2048
 
 /// Wrapper for turning a File into a BlockDevice
 struct BlockFile(Mutex<File>);
@@ -27,15 +31,43 @@ impl BlockDevice for BlockFile {
             .expect("Error when seeking!");
         assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
     }
+    /// Get the number of blocks in file
+    fn num_blocks(&self) -> Option<usize> {
+        let file = self.0.lock().unwrap();
+        file.metadata().ok().map(|metadata| metadata.len() as usize / BLOCK_SZ)
+    }
 }
 
 fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+    easy_fs::set_clock(host_clock);
+    let matches = app().get_matches();
+    match matches.subcommand() {
+        ("fsck", Some(matches)) => {
+            if !easy_fs_fsck(matches).expect("Error when checking easy-fs!") {
+                std::process::exit(1);
+            }
+        }
+        ("ls", Some(matches)) => {
+            easy_fs_ls(matches, &mut std::io::stdout()).expect("Error when listing easy-fs!")
+        }
+        ("extract", Some(matches)) => {
+            easy_fs_extract(matches).expect("Error when extracting easy-fs!")
+        }
+        ("inspect", Some(matches)) => {
+            easy_fs_inspect(matches, &mut std::io::stdout())
+                .expect("Error when inspecting easy-fs!")
+        }
+        ("add", Some(matches)) => easy_fs_add(matches).expect("Error when adding to easy-fs!"),
+        ("rm", Some(matches)) => easy_fs_rm(matches).expect("Error when removing from easy-fs!"),
+        #[cfg(feature = "fuse")]
+        ("mount", Some(matches)) => easy_fs_mount(matches).expect("Error when mounting easy-fs!"),
+        _ => easy_fs_pack(&matches).expect("Error when packing easy-fs!"),
+    }
 }
 
-/// Pack a directory into a easy-fs disk image
-fn easy_fs_pack() -> std::io::Result<()> {
-    let matches = App::new("EasyFileSystem packer")
+/// The command line arguments of the packer and its subcommands
+fn app() -> App<'static, 'static> {
+    let app = App::new("EasyFileSystem packer")
         .arg(
             Arg::with_name("source")
                 .short("s")
@@ -50,9 +82,177 @@ fn easy_fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .default_value("64M")
+                .help("Size of the image, in bytes or with a K, M or G suffix"),
+        )
+        .arg(
+            Arg::with_name("extents")
+                .long("extents")
+                .help("Describe files by extents of contiguous blocks"),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check a disk image")
+                .arg(image_arg())
+                .arg(
+                    Arg::with_name("repair")
+                        .short("r")
+                        .long("repair")
+                        .help("Fix the problems found"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List the files of a disk image with their sizes")
+                .arg(image_arg())
+                .arg(
+                    Arg::with_name("path")
+                        .index(2)
+                        .default_value("/")
+                        .help("Directory to list"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Copy files out of a disk image")
+                .arg(image_arg())
+                .arg(
+                    Arg::with_name("dest")
+                        .required(true)
+                        .index(2)
+                        .help("Host directory to copy the files into"),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .index(3)
+                        .multiple(true)
+                        .help("Files or directories to copy, everything by default"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Show the super block and the usage of a disk image")
+                .arg(image_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Copy a host file into a disk image")
+                .arg(image_arg())
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .index(2)
+                        .help("Host file to copy"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .index(3)
+                        .help("Path of the copy, the name of the file in the root by default"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a file or an empty directory from a disk image")
+                .arg(image_arg())
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .index(2)
+                        .help("Path to remove"),
+                ),
+        );
+    #[cfg(feature = "fuse")]
+    let app = app.subcommand(
+        SubCommand::with_name("mount")
+            .about("Mount a disk image on the host until it is unmounted")
+            .arg(image_arg())
+            .arg(
+                Arg::with_name("mountpoint")
+                    .required(true)
+                    .index(2)
+                    .help("Host directory to mount the image on"),
+            ),
+    );
+    app
+}
+
+/// The disk image argument shared by all subcommands
+fn image_arg<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("image")
+        .required(true)
+        .index(1)
+        .help("Disk image to work on")
+}
+
+/// Open an existing disk image
+fn open_image(image_path: &str) -> std::io::Result<Arc<BlockFile>> {
+    // opening the filesystem may replay its journal, so it is always writable
+    let f = OpenOptions::new().read(true).write(true).open(image_path)?;
+    Ok(Arc::new(BlockFile(Mutex::new(f))))
+}
+
+/// Turn a missing file in the image into an io error
+fn not_found(path: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{}: No such file or directory", path),
+    )
+}
+
+/// Seconds since the epoch on the host, which inode timestamps are taken from
+fn host_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Give an inode the permissions, the owner and the modification time of a host file
+fn copy_metadata(inode: &Inode, host_metadata: &std::fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+    inode.set_metadata(Metadata {
+        perm: (host_metadata.mode() & 0o7777) as u16,
+        uid: host_metadata.uid(),
+        gid: host_metadata.gid(),
+        mtime: host_metadata.mtime().max(0) as u64,
+        ..inode.metadata()
+    });
+}
+
+/// Parse an image size such as "64M" into a number of blocks
+fn parse_size(size: &str) -> std::io::Result<u32> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{}: Image size must be a multiple of 512 bytes from 1M to 2T", size),
+        )
+    };
+    let (digits, unit) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 1u64 << 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 1u64 << 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 1u64 << 30),
+        _ => (size, 1u64),
+    };
+    let bytes = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(invalid)?;
+    let blocks = bytes / BLOCK_SZ as u64;
+    if bytes % BLOCK_SZ as u64 != 0 || bytes < 1 << 20 || blocks > u32::MAX as u64 {
+        return Err(invalid());
+    }
+    Ok(blocks as u32)
+}
+
+/// Pack a directory into a easy-fs disk image
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
     let src_path = matches.value_of("source").unwrap();
     let target_path = matches.value_of("target").unwrap();
+    let block_num = parse_size(matches.value_of("size").unwrap())?;
     println!("src_path = {}\ntarget_path = {}", src_path, target_path);
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
@@ -60,11 +260,13 @@ fn easy_fs_pack() -> std::io::Result<()> {
             .write(true)
             .create(true)
             .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
+        f.set_len(block_num as u64 * BLOCK_SZ as u64).unwrap();
         f
     })));
-    let efs = EasyFileSystem::create(block_file.clone(), BLOCK_NUM as u32, 1);
+    let extents = matches.is_present("extents");
+    let efs = EasyFileSystem::create_with_layout(block_file.clone(), block_num, 1, extents);
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+    copy_metadata(&root_inode, &std::fs::metadata(src_path)?);
     let apps: Vec<_> = read_dir(src_path)
         .unwrap()
         .into_iter()
@@ -83,7 +285,10 @@ fn easy_fs_pack() -> std::io::Result<()> {
         let inode = root_inode.create(app.as_str()).unwrap();
         // write data to easy-fs
         inode.write_at(0, all_data.as_slice());
+        copy_metadata(&inode, &host_file.metadata()?);
     }
+    // write everything back before the image is used
+    efs.lock().sync();
     // list apps
     for app in root_inode.ls() {
         println!("{}", app);
@@ -91,6 +296,200 @@ fn easy_fs_pack() -> std::io::Result<()> {
     Ok(())
 }
 
+/// Check a easy-fs disk image, and repair it if asked to.
+/// Returns whether the image is consistent in the end
+fn easy_fs_fsck(matches: &ArgMatches) -> std::io::Result<bool> {
+    let image_path = matches.value_of("image").unwrap();
+    let repair = matches.is_present("repair");
+    let block_device: Arc<dyn BlockDevice> = open_image(image_path)?;
+    // an image whose super block is bad cannot even be opened
+    let mut problems = EasyFileSystem::check_super_block(&block_device);
+    let efs = problems.is_empty().then(|| EasyFileSystem::open(block_device));
+    if let Some(efs) = efs.as_ref() {
+        problems = efs.lock().check(repair);
+    }
+    for problem in problems.iter() {
+        if repair && problem.is_repairable() {
+            println!("{} (repaired)", problem);
+        } else {
+            println!("{}", problem);
+        }
+    }
+    if let Some(efs) = efs {
+        efs.lock().sync();
+    }
+    println!("{}: {} problem(s) found", image_path, problems.len());
+    Ok(problems.iter().all(|problem| repair && problem.is_repairable()))
+}
+
+/// List a directory of a disk image recursively into `out`
+fn easy_fs_ls(matches: &ArgMatches, out: &mut dyn Write) -> std::io::Result<()> {
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let path = matches.value_of("path").unwrap();
+    let inode = root_inode.find_path(path).ok_or_else(|| not_found(path))?;
+    list(&inode, path.trim_end_matches('/'), out)
+}
+
+/// Write the size and path of everything under a directory
+fn list(dir: &Inode, dir_path: &str, out: &mut dyn Write) -> std::io::Result<()> {
+    for name in dir.ls() {
+        let inode = dir.find(&name).unwrap();
+        let path = format!("{}/{}", dir_path, name);
+        if inode.is_dir() {
+            writeln!(out, "{:>10} {}/", "-", path)?;
+            list(&inode, &path, out)?;
+        } else {
+            writeln!(out, "{:>10} {}", inode.size(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy files or directories out of a disk image
+fn easy_fs_extract(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dest = Path::new(matches.value_of("dest").unwrap());
+    create_dir_all(dest)?;
+    match matches.values_of("paths") {
+        Some(paths) => {
+            for path in paths {
+                let inode = root_inode.find_path(path).ok_or_else(|| not_found(path))?;
+                let name = path.trim_end_matches('/').rsplit('/').next().unwrap();
+                // extracting the root puts its contents right into dest
+                extract(&inode, &dest.join(name))?;
+            }
+        }
+        None => extract(&root_inode, dest)?,
+    }
+    Ok(())
+}
+
+/// Copy a file or a directory with everything under it to the host
+fn extract(inode: &Inode, host_path: &Path) -> std::io::Result<()> {
+    if inode.is_dir() {
+        create_dir_all(host_path)?;
+        for name in inode.ls() {
+            extract(&inode.find(&name).unwrap(), &host_path.join(&name))?;
+        }
+    } else {
+        let mut data = vec![0u8; inode.size() as usize];
+        assert_eq!(inode.read_at(0, &mut data), data.len());
+        File::create(host_path)?.write_all(&data)?;
+        println!("{}", host_path.display());
+    }
+    Ok(())
+}
+
+/// Show the super block and how much of a disk image is in use in `out`
+fn easy_fs_inspect(matches: &ArgMatches, out: &mut dyn Write) -> std::io::Result<()> {
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    let efs = efs.lock();
+    writeln!(out, "{:#?}", efs.super_block())?;
+    let usage = efs.usage();
+    writeln!(out, "inodes: {} / {} used", usage.used_inodes, usage.inodes)?;
+    writeln!(
+        out,
+        "data blocks: {} / {} used ({} KiB free)",
+        usage.used_data_blocks,
+        usage.data_blocks,
+        (usage.data_blocks - usage.used_data_blocks) * BLOCK_SZ / 1024,
+    )
+}
+
+/// Copy a host file into a disk image, replacing the file already there
+fn easy_fs_add(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let host_path = Path::new(matches.value_of("file").unwrap());
+    let path = match matches.value_of("path") {
+        Some(path) => String::from(path),
+        None => format!("/{}", host_path.file_name().unwrap().to_string_lossy()),
+    };
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path.as_str()));
+    let dir = root_inode
+        .find_path(if parent.is_empty() { "/" } else { parent })
+        .filter(|dir| dir.is_dir())
+        .ok_or_else(|| not_found(parent))?;
+    let inode = match dir.find(name) {
+        Some(inode) if !inode.is_dir() => {
+            inode.clear();
+            inode
+        }
+        Some(_) => return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{}: Is a directory", path),
+        )),
+        None if name.is_empty() || name.len() > NAME_LENGTH_LIMIT => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{}: Invalid file name", path),
+            ))
+        }
+        // the name is free and valid, so only the inodes can have run out
+        None => dir.create(name).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{}: No inode left", path),
+        ))?,
+    };
+    let mut data: Vec<u8> = Vec::new();
+    let mut host_file = File::open(host_path)?;
+    host_file.read_to_end(&mut data)?;
+    if inode.write_at(0, &data) < data.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{}: File too large", path),
+        ));
+    }
+    copy_metadata(&inode, &host_file.metadata()?);
+    efs.lock().sync();
+    Ok(())
+}
+
+/// Remove a file or an empty directory from a disk image
+fn easy_fs_rm(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let path = matches.value_of("path").unwrap().trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let dir = root_inode
+        .find_path(if parent.is_empty() { "/" } else { parent })
+        .ok_or_else(|| not_found(path))?;
+    if name == "." || name == ".." {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{}: Invalid argument", path),
+        ));
+    }
+    let inode = dir.find(name).ok_or_else(|| not_found(path))?;
+    if inode.is_dir() && !inode.ls().is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{}: Directory not empty", path),
+        ));
+    }
+    let removed = if inode.is_dir() { dir.rmdir(name) } else { dir.unlink(name) };
+    assert!(removed, "An empty directory or a file can always be removed!");
+    efs.lock().sync();
+    Ok(())
+}
+
+/// Serve a disk image through FUSE until it is unmounted
+#[cfg(feature = "fuse")]
+fn easy_fs_mount(matches: &ArgMatches) -> std::io::Result<()> {
+    use fuser::MountOption;
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    fuser::mount2(
+        fuse::EasyFuse::new(efs),
+        matches.value_of("mountpoint").unwrap(),
+        &[
+            MountOption::FSName(String::from("easy-fs")),
+            MountOption::DefaultPermissions,
+        ],
+    )
+}
+
 #[test]
 fn efs_test() -> std::io::Result<()> {
     let block_file = Arc::new(BlockFile(Mutex::new({
@@ -99,7 +498,7 @@ fn efs_test() -> std::io::Result<()> {
             .write(true)
             .create(true)
             .open("target/fs.img")?;
-        f.set_len(BLOCK_NUM * BLOCK_SZ).unwrap();
+        f.set_len((4096 * BLOCK_SZ) as u64).unwrap();
         f
     })));
     EasyFileSystem::create(block_file.clone(), 4096, 1);
@@ -151,5 +550,811 @@ fn efs_test() -> std::io::Result<()> {
     random_str_test(1000 * BLOCK_SZ);
     random_str_test(2000 * BLOCK_SZ);
 
+    // directories and path resolution
+    let bin = root_inode.mkdir("bin").unwrap();
+    assert!(root_inode.mkdir("bin").is_none());
+    let sh = bin.create("sh").unwrap();
+    sh.write_at(0, greet_str.as_bytes());
+    let sh = root_inode.find_path("/bin/sh").unwrap();
+    let len = sh.read_at(0, &mut buffer);
+    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
+    assert_eq!(bin.find_path("./sh").unwrap().inode_id(), sh.inode_id());
+    assert_eq!(bin.find_path("..").unwrap().inode_id(), root_inode.inode_id());
+    assert_eq!(root_inode.find_path("/..").unwrap().inode_id(), root_inode.inode_id());
+    assert!(root_inode.find_path("/bin/sh/..").is_none());
+    assert_eq!(bin.ls(), vec!["sh"]);
+    // only empty directories can be removed
+    assert!(!root_inode.rmdir("bin"));
+    let usr = root_inode.mkdir("usr").unwrap();
+    assert!(usr.mkdir("lib").is_some());
+    assert!(!root_inode.rmdir("usr"));
+    assert!(usr.rmdir("lib"));
+    assert!(root_inode.rmdir("usr"));
+    assert!(root_inode.find("usr").is_none());
+    assert_eq!(root_inode.ls(), vec!["filea", "fileb", "bin"]);
+    // hard links share the same inode
+    assert!(bin.link("sh2", &sh));
+    assert!(!root_inode.link("bin2", &bin));
+    assert_eq!(root_inode.find_path("bin/sh2").unwrap().inode_id(), sh.inode_id());
+    assert_eq!(sh.nlink(), 2);
+    assert!(bin.unlink("sh"));
+    assert!(!bin.unlink("sh"));
+    assert!(!root_inode.unlink("bin"));
+    assert_eq!(bin.ls(), vec!["sh2"]);
+    assert_eq!(sh.nlink(), 1);
+    // the inode is reclaimed together with its last name
+    assert!(bin.unlink("sh2"));
+    assert_eq!(bin.create("sh3").unwrap().inode_id(), sh.inode_id());
+
+    Ok(())
+}
+
+/// Open a disk image of the given number of blocks for a test,
+/// creating it if it is not there yet
+#[cfg(test)]
+fn test_image(path: &str, blocks: u64) -> std::io::Result<Arc<BlockFile>> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?;
+    f.set_len(blocks * BLOCK_SZ as u64)?;
+    Ok(Arc::new(BlockFile(Mutex::new(f))))
+}
+
+#[test]
+fn efs_reclaim_test() -> std::io::Result<()> {
+    let block_file = test_image("target/fs_reclaim.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let inode_num = efs.lock().inode_bitmap.maximum();
+    let data = [0x5au8; 2 * BLOCK_SZ];
+    // go through more create/delete cycles than there are inodes,
+    // which would run out of inodes or data blocks if any of them leaked
+    for _ in 0..inode_num + 16 {
+        let file = root_inode.create("file").unwrap();
+        assert_eq!(file.write_at(0, &data), data.len());
+        assert!(EasyFileSystem::get_inode(&efs, file.inode_id()).is_some());
+        assert!(root_inode.unlink("file"));
+        assert!(EasyFileSystem::get_inode(&efs, file.inode_id()).is_none());
+        let dir = root_inode.mkdir("dir").unwrap();
+        assert!(dir.create("file").is_some());
+        assert!(dir.unlink("file"));
+        assert!(root_inode.rmdir("dir"));
+    }
+    assert!(root_inode.ls().is_empty());
+    // only the root and its dirents are left
+    let usage = efs.lock().usage();
+    assert_eq!((usage.used_inodes, usage.used_data_blocks), (1, 1));
+    Ok(())
+}
+
+#[test]
+fn efs_open_unlink_test() -> std::io::Result<()> {
+    let block_file = test_image("target/fs_open_unlink.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used_inodes = || efs.lock().usage().used_inodes;
+    let file = root_inode.create("file").unwrap();
+    assert_eq!(file.write_at(0, b"still here"), 10);
+    file.open_handle();
+    let inodes = used_inodes();
+    // an open file outlives its last name, and its inode is not handed out again
+    assert!(root_inode.unlink("file"));
+    assert!(root_inode.find("file").is_none());
+    let other = root_inode.create("other").unwrap();
+    assert_ne!(other.inode_id(), file.inode_id());
+    assert_eq!(other.write_at(0, b"other data"), 10);
+    let mut buffer = [0u8; 10];
+    assert_eq!(file.read_at(0, &mut buffer), 10);
+    assert_eq!(&buffer, b"still here");
+    assert!(efs.lock().check(false).is_empty());
+    // the last handle takes it along
+    file.close_handle();
+    assert_eq!(used_inodes(), inodes);
+    // the same goes for a file replaced by a rename
+    let victim = root_inode.create("victim").unwrap();
+    victim.open_handle();
+    victim.open_handle();
+    assert!(root_inode.rename("other", &root_inode, "victim"));
+    assert_eq!(victim.nlink(), 0);
+    victim.close_handle();
+    assert_eq!(used_inodes(), inodes + 1);
+    victim.close_handle();
+    assert_eq!(used_inodes(), inodes);
+    // and for a removed directory, which takes no new names meanwhile
+    let dir = root_inode.mkdir("dir").unwrap();
+    dir.open_handle();
+    assert!(root_inode.rmdir("dir"));
+    assert!(dir.create("file").is_none());
+    assert!(dir.mkdir("dir").is_none());
+    dir.close_handle();
+    assert_eq!(used_inodes(), inodes);
+    assert!(efs.lock().check(false).is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_cache_test() -> std::io::Result<()> {
+    let block_file = test_image("target/fs_cache.img", 4096)?;
+    EasyFileSystem::create(block_file.clone(), 4096, 1);
+    // a file much larger than the cache keeps evicting dirty blocks
+    let efs = EasyFileSystem::open_with_cache(block_file.clone(), 8);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // other tests share the cache, so only the counters of this image are looked at
+    let before = efs.lock().cache_stats();
+    let file = root_inode.create("big").unwrap();
+    let data: Vec<u8> = (0..64 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    assert_eq!(file.write_at(0, &data), data.len());
+    let mut buffer = vec![0u8; data.len()];
+    assert_eq!(file.read_at(0, &mut buffer), data.len());
+    assert_eq!(buffer, data);
+    let after = efs.lock().cache_stats();
+    assert!(after.hits > before.hits);
+    assert!(after.misses > before.misses);
+    assert!(after.writebacks > before.writebacks);
+    Ok(())
+}
+
+#[test]
+fn efs_fsync_test() -> std::io::Result<()> {
+    let _ = std::fs::remove_file("target/fs_fsync.img");
+    let block_file = test_image("target/fs_fsync.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("file").unwrap();
+    let data: Vec<u8> = (0..40 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    assert_eq!(file.write_at(0, &data), data.len());
+    file.fsync();
+    // every data block has reached the image once the file is synced
+    let image = std::fs::read("target/fs_fsync.img")?;
+    let blocks: std::collections::HashSet<&[u8]> = image.chunks_exact(BLOCK_SZ).collect();
+    assert!(data.chunks_exact(BLOCK_SZ).all(|block| blocks.contains(block)));
+    Ok(())
+}
+
+/// Wrapper for a BlockDevice which stops writing to it after the first
+/// few writes, as if the machine crashed at that point. Later writes are
+/// still seen by reads through the wrapper so that the running filesystem
+/// goes on undisturbed.
+#[cfg(test)]
+struct CrashDevice {
+    inner: Arc<dyn BlockDevice>,
+    writes_left: Mutex<usize>,
+    lost_writes: Mutex<std::collections::HashMap<usize, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl CrashDevice {
+    fn new(inner: Arc<dyn BlockDevice>, writes: usize) -> Self {
+        Self {
+            inner,
+            writes_left: Mutex::new(writes),
+            lost_writes: Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+    /// Whether any write has been lost
+    fn crashed(&self) -> bool {
+        !self.lost_writes.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for CrashDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        match self.lost_writes.lock().unwrap().get(&block_id) {
+            Some(data) => buf.copy_from_slice(data),
+            None => self.inner.read_block(block_id, buf),
+        }
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut writes_left = self.writes_left.lock().unwrap();
+        if *writes_left > 0 {
+            *writes_left -= 1;
+            self.inner.write_block(block_id, buf);
+        } else {
+            self.lost_writes.lock().unwrap().insert(block_id, buf.to_vec());
+        }
+    }
+    fn num_blocks(&self) -> Option<usize> {
+        self.inner.num_blocks()
+    }
+}
+
+#[test]
+fn efs_journal_test() -> std::io::Result<()> {
+    EasyFileSystem::create(test_image("target/fs_journal_clean.img", 4096)?, 4096, 1);
+    let data = [0x3cu8; 30 * BLOCK_SZ];
+    // crash after every possible number of writes, until the workload survives
+    for writes in 0.. {
+        // blocks cached for the previous image must not be written into this one
+        let _ = std::fs::remove_file("target/fs_journal.img");
+        std::fs::copy("target/fs_journal_clean.img", "target/fs_journal.img")?;
+        let device = Arc::new(CrashDevice::new(test_image("target/fs_journal.img", 4096)?, writes));
+        let efs = EasyFileSystem::open(device.clone());
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let dir = root_inode.mkdir("dir").unwrap();
+        let file = dir.create("file").unwrap();
+        assert_eq!(file.write_at(0, &data), data.len());
+        assert!(root_inode.create("other").is_some());
+        assert!(root_inode.link("alias", &file));
+        assert!(dir.unlink("file"));
+        efs.lock().sync();
+        // recover through a fresh handle on the image,
+        // where a crash between the transactions freeing a file only leaks
+        let efs = EasyFileSystem::open(test_image("target/fs_journal.img", 4096)?);
+        assert!(efs.lock().check(true).iter().all(|problem| matches!(
+            problem,
+            easy_fs::FsckProblem::LeakedInode(_) | easy_fs::FsckProblem::LeakedBlock(_)
+        )));
+        // and remove everything that survived the crash
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        for name in root_inode.ls() {
+            let inode = root_inode.find(&name).unwrap();
+            if inode.is_dir() {
+                for name in inode.ls() {
+                    assert!(inode.unlink(&name));
+                }
+                assert!(root_inode.rmdir(&name));
+            } else {
+                assert!(root_inode.unlink(&name));
+            }
+        }
+        // all inodes but the root and all data blocks but its dirents are free again
+        let mut fs = efs.lock();
+        let inode_num = fs.inode_bitmap.maximum();
+        assert_eq!((0..).take_while(|_| fs.alloc_inode().is_some()).count(), inode_num - 1);
+        let data_num = fs.data_bitmap.maximum();
+        let block_device = Arc::clone(&fs.block_device);
+        assert_eq!(
+            (0..).take_while(|_| fs.data_bitmap.alloc(&block_device).is_some()).count(),
+            data_num - 1
+        );
+        if !device.crashed() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_fsck_test() -> std::io::Result<()> {
+    use easy_fs::FsckProblem;
+    let block_file = test_image("target/fs_fsck.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.mkdir("dir").unwrap();
+    let data = [0x42u8; 200 * BLOCK_SZ];
+    let file = dir.create("file").unwrap();
+    assert_eq!(file.write_at(0, &data), data.len());
+    assert!(root_inode.link("alias", &file));
+    let victim = root_inode.create("victim").unwrap();
+    assert_eq!(victim.write_at(0, &data[..4 * BLOCK_SZ]), 4 * BLOCK_SZ);
+    assert!(efs.lock().check(false).is_empty());
+    // an inode and a block nothing refers to,
+    // a dirent to an inode which is gone with its blocks still in use,
+    // and a ".." referring to a file rather than the parent
+    let leaked_inode = efs.lock().alloc_inode().unwrap();
+    let leaked_block = efs.lock().alloc_data();
+    efs.lock().dealloc_inode(victim.inode_id());
+    let (_, dotdot_offset) = dir.read_dir(0).unwrap();
+    assert_eq!(dir.read_dir(dotdot_offset).unwrap().0.name, "..");
+    assert_eq!(dir.write_at(dotdot_offset, &(file.inode_id() + 1).to_le_bytes()), 4);
+    let problems = efs.lock().check(false);
+    // the dirents are found walking the tree, then the leaks in the order of the bitmaps,
+    // the blocks of the victim lying right before the leaked block
+    let mut expected = vec![
+        FsckProblem::DanglingDirent {
+            dir: 0,
+            name: String::from("victim"),
+            inode_id: victim.inode_id(),
+        },
+        FsckProblem::BadParent {
+            dir: dir.inode_id(),
+            parent: 0,
+            dotdot: Some(file.inode_id()),
+        },
+        FsckProblem::LeakedInode(leaked_inode),
+    ];
+    expected.extend((leaked_block - 4..=leaked_block).map(FsckProblem::LeakedBlock));
+    assert_eq!(problems, expected);
+    assert!(problems.iter().all(|problem| problem.is_repairable()));
+    // nothing is left to fix once repaired
+    assert_eq!(efs.lock().check(true), problems);
+    assert!(efs.lock().check(false).is_empty());
+    assert!(root_inode.find("victim").is_none());
+    let mut buffer = vec![0u8; data.len()];
+    assert_eq!(root_inode.find("alias").unwrap().read_at(0, &mut buffer), data.len());
+    assert_eq!(buffer, data);
+    Ok(())
+}
+
+#[test]
+fn efs_fsck_size_test() -> std::io::Result<()> {
+    use easy_fs::FsckProblem;
+    for extents in [false, true] {
+        let block_file = test_image("target/fs_fsck_size.img", 4096)?;
+        let efs = EasyFileSystem::create_with_layout(block_file.clone(), 4096, 1, extents);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        // past the direct blocks and into the doubly indirect ones, or a single extent
+        let data = [0x42u8; 200 * BLOCK_SZ];
+        let file = root_inode.create("file").unwrap();
+        assert_eq!(file.write_at(0, &data), data.len());
+        efs.lock().sync();
+        let (block_id, block_offset) = efs.lock().get_disk_inode_pos(file.inode_id());
+        // shrink the size on the image alone, which leaves the blocks past it referred to,
+        // and look at it through a handle of its own so that no cached block hides the change
+        let size = 20 * BLOCK_SZ as u32 + 100;
+        let mut image = std::fs::read("target/fs_fsck_size.img")?;
+        let offset = block_id as usize * BLOCK_SZ + block_offset;
+        image[offset..offset + 4].copy_from_slice(&size.to_le_bytes());
+        std::fs::write("target/fs_fsck_size.img", &image)?;
+        let efs = EasyFileSystem::open(open_image("target/fs_fsck_size.img")?);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let file = root_inode.find("file").unwrap();
+        let problems = efs.lock().check(false);
+        assert_eq!(problems[0], FsckProblem::BlocksPastSize { inode_id: file.inode_id(), size });
+        // the data blocks from the 22nd on, and the doubly indirect block with the one under it
+        let index_blocks = if extents { 0 } else { 2 };
+        assert_eq!(problems.len(), 1 + (200 - 21) + index_blocks);
+        assert!(problems[1..].iter().all(|problem| matches!(problem, FsckProblem::LeakedBlock(_))));
+        assert_eq!(efs.lock().check(true), problems);
+        assert!(efs.lock().check(false).is_empty());
+        // the freed blocks go to another file, while the first one reads zeros past its size
+        let other = root_inode.create("other").unwrap();
+        assert_eq!(other.write_at(0, &[0x17u8; 180 * BLOCK_SZ]), 180 * BLOCK_SZ);
+        assert!(file.truncate(data.len() as u32));
+        let mut buffer = vec![0u8; data.len()];
+        assert_eq!(file.read_at(0, &mut buffer), data.len());
+        assert_eq!(&buffer[..size as usize], &data[..size as usize]);
+        assert!(buffer[size as usize..].iter().all(|&byte| byte == 0));
+        // and shrinking it again frees nothing twice
+        assert!(file.truncate(0));
+        assert!(efs.lock().check(false).is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_fsck_super_block_test() -> std::io::Result<()> {
+    use easy_fs::FsckProblem;
+    EasyFileSystem::create(test_image("target/fs_super_block.img", 4096)?, 4096, 1);
+    let image = std::fs::read("target/fs_super_block.img")?;
+    // check a copy of the image changed by `change`,
+    // through a handle of its own so that no cached block hides the change
+    let check_changed = |change: &dyn Fn(&mut Vec<u8>)| -> std::io::Result<Vec<FsckProblem>> {
+        let mut image = image.clone();
+        change(&mut image);
+        std::fs::write("target/fs_super_block_copy.img", &image)?;
+        let block_device: Arc<dyn BlockDevice> = open_image("target/fs_super_block_copy.img")?;
+        Ok(EasyFileSystem::check_super_block(&block_device))
+    };
+    let set_field = |image: &mut Vec<u8>, offset: usize, value: u32| {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    assert!(check_changed(&|_| {})?.is_empty());
+    assert_eq!(check_changed(&|image| image[0] = 0)?, vec![FsckProblem::BadMagic]);
+    assert_eq!(
+        check_changed(&|image| image.truncate(4000 * BLOCK_SZ))?,
+        vec![FsckProblem::DeviceTooSmall { total_blocks: 4096, device_blocks: 4000 }]
+    );
+    // more data blocks than the data bitmap covers, and than the total leaves room for
+    assert_eq!(
+        check_changed(&|image| set_field(image, 20, 5000))?,
+        vec![
+            FsckProblem::BadSuperBlock { field: "data_bitmap_blocks", value: 1 },
+            FsckProblem::BadSuperBlock { field: "total_blocks", value: 4096 },
+        ]
+    );
+    assert!(check_changed(&|image| set_field(image, 24, 1))?
+        .contains(&FsckProblem::BadSuperBlock { field: "journal_blocks", value: 1 }));
+    Ok(())
+}
+
+#[test]
+fn efs_truncate_test() -> std::io::Result<()> {
+    let block_file = test_image("target/fs_truncate.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used_data_blocks = || efs.lock().usage().used_data_blocks;
+    let empty = used_data_blocks();
+    let file = root_inode.create("file").unwrap();
+    // a block at the start and one reached through indirect2,
+    // with a hole of more than a thousand blocks in between
+    let data = [0x6bu8; BLOCK_SZ];
+    let far = 1200 * BLOCK_SZ;
+    assert_eq!(file.write_at(0, &data), BLOCK_SZ);
+    assert_eq!(file.write_at(far, &data), BLOCK_SZ);
+    assert_eq!(file.size() as usize, far + BLOCK_SZ);
+    // the two data blocks, indirect2 and the index block under it
+    assert_eq!(used_data_blocks(), empty + 4);
+    let mut buffer = [0xffu8; BLOCK_SZ];
+    assert_eq!(file.read_at(600 * BLOCK_SZ, &mut buffer), BLOCK_SZ);
+    assert!(buffer.iter().all(|&b| b == 0));
+    assert!(efs.lock().check(false).is_empty());
+    // shrinking frees the blocks past the new size and zeros the rest of the last one
+    assert!(file.truncate(100));
+    assert_eq!(used_data_blocks(), empty + 1);
+    assert!(file.truncate(2 * BLOCK_SZ as u32));
+    assert_eq!(used_data_blocks(), empty + 1);
+    let mut buffer = [0xffu8; 2 * BLOCK_SZ];
+    assert_eq!(file.read_at(0, &mut buffer), 2 * BLOCK_SZ);
+    assert!(buffer[..100].iter().all(|&b| b == 0x6b));
+    assert!(buffer[100..].iter().all(|&b| b == 0));
+    // writing into a hole fills it
+    assert_eq!(file.write_at(BLOCK_SZ + 10, &data[..10]), 10);
+    assert_eq!(used_data_blocks(), empty + 2);
+    assert!(efs.lock().check(false).is_empty());
+    assert!(file.truncate(0));
+    assert_eq!(used_data_blocks(), empty);
+    assert!(!root_inode.truncate(0));
+    Ok(())
+}
+
+#[test]
+fn efs_large_file_test() -> std::io::Result<()> {
+    assert_eq!(parse_size("64M")?, 131072);
+    assert_eq!(parse_size("2097152")?, 4096);
+    assert!(parse_size("64X").is_err());
+    assert!(parse_size("1000000").is_err());
+    assert!(parse_size("4T").is_err());
+    let block_file = test_image("target/fs_large.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    assert_eq!(efs.lock().super_block().version, 6);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used_data_blocks = || efs.lock().usage().used_data_blocks;
+    let empty = used_data_blocks();
+    let file = root_inode.create("file").unwrap();
+    // past the reach of indirect2, about 8 MiB
+    let data = [0x3cu8; 2 * BLOCK_SZ];
+    let far = 20000 * BLOCK_SZ;
+    assert_eq!(file.write_at(far, &data), data.len());
+    // the two data blocks, indirect3 and one index block on each level under it
+    assert_eq!(used_data_blocks(), empty + 5);
+    let mut buffer = [0u8; 2 * BLOCK_SZ];
+    assert_eq!(file.read_at(far, &mut buffer), data.len());
+    assert_eq!(buffer, data);
+    assert!(efs.lock().check(false).is_empty());
+    // up to the max size of about 1 GiB and no further
+    let max_size = easy_fs::MAX_FILE_SIZE as usize;
+    assert_eq!(file.write_at(max_size - 10, &data), 10);
+    assert_eq!(file.write_at(max_size, &data), 0);
+    assert_eq!(file.size() as usize, max_size);
+    assert!(!file.truncate(max_size as u32 + 1));
+    assert!(file.truncate(far as u32 + 1));
+    assert_eq!(used_data_blocks(), empty + 4);
+    assert!(file.truncate(0));
+    assert_eq!(used_data_blocks(), empty);
+    Ok(())
+}
+
+#[test]
+fn efs_extent_test() -> std::io::Result<()> {
+    let block_file = test_image("target/fs_extent.img", 4096)?;
+    let efs = EasyFileSystem::create_with_layout(block_file.clone(), 4096, 1, true);
+    assert_eq!(efs.lock().super_block().version, 7);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used_data_blocks = || efs.lock().usage().used_data_blocks;
+    let empty = used_data_blocks();
+    // a file written in one go is a single extent, needing no index blocks
+    let seq = root_inode.create("seq").unwrap();
+    let data: Vec<u8> = (0..300 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    assert_eq!(seq.write_at(0, &data), data.len());
+    assert_eq!(used_data_blocks(), empty + 300);
+    let mut buffer = vec![0u8; data.len()];
+    assert_eq!(seq.read_at(0, &mut buffer), data.len());
+    assert_eq!(buffer, data);
+    // appending to two files in turn splits both into many extents,
+    // more than fit in the inode
+    let a = root_inode.create("a").unwrap();
+    let b = root_inode.create("b").unwrap();
+    for i in 0..40 {
+        let offset = i * BLOCK_SZ;
+        assert_eq!(a.write_at(offset, &[i as u8 + 1; BLOCK_SZ]), BLOCK_SZ);
+        assert_eq!(b.write_at(offset, &[i as u8 + 101; BLOCK_SZ]), BLOCK_SZ);
+    }
+    // one extent block for each
+    assert_eq!(used_data_blocks(), empty + 300 + 80 + 2);
+    for i in 0..40 {
+        let mut buffer = [0u8; BLOCK_SZ];
+        assert_eq!(b.read_at(i * BLOCK_SZ, &mut buffer), BLOCK_SZ);
+        assert!(buffer.iter().all(|&x| x == i as u8 + 101));
+    }
+    assert!(efs.lock().check(false).is_empty());
+    // holes read as zeros and take no blocks
+    let sparse = root_inode.create("sparse").unwrap();
+    assert_eq!(sparse.write_at(1000 * BLOCK_SZ, &data[..BLOCK_SZ]), BLOCK_SZ);
+    assert_eq!(used_data_blocks(), empty + 300 + 82 + 1);
+    let mut buffer = [0xffu8; BLOCK_SZ];
+    assert_eq!(sparse.read_at(500 * BLOCK_SZ, &mut buffer), BLOCK_SZ);
+    assert!(buffer.iter().all(|&x| x == 0));
+    assert!(efs.lock().check(false).is_empty());
+    // shrinking gives back data blocks and extent blocks
+    assert!(a.truncate(5 * BLOCK_SZ as u32));
+    assert_eq!(used_data_blocks(), empty + 300 + 40 + 5 + 1 + 1);
+    for file in [&seq, &a, &b, &sparse] {
+        assert!(file.truncate(0));
+    }
+    assert_eq!(used_data_blocks(), empty);
+    assert!(efs.lock().check(false).is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_metadata_test() -> std::io::Result<()> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static TIME: AtomicU64 = AtomicU64::new(1_000_000);
+    fn test_clock() -> u64 {
+        TIME.load(Ordering::SeqCst)
+    }
+    /// Put the clock the other tests use back once this one ends, even by panicking
+    struct RestoreClock(fn() -> u64);
+    impl Drop for RestoreClock {
+        fn drop(&mut self) {
+            easy_fs::set_clock(self.0);
+        }
+    }
+    let _restore = RestoreClock(easy_fs::set_clock(test_clock));
+    let block_file = test_image("target/fs_metadata.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("file").unwrap();
+    let created = Metadata {
+        perm: 0o644,
+        uid: 0,
+        gid: 0,
+        atime: 1_000_000,
+        mtime: 1_000_000,
+        ctime: 1_000_000,
+    };
+    assert_eq!(file.metadata(), created);
+    assert_eq!(root_inode.mkdir("dir").unwrap().metadata().perm, 0o755);
+    // writes change mtime and ctime
+    TIME.store(1_000_010, Ordering::SeqCst);
+    assert_eq!(file.write_at(0, b"hello"), 5);
+    let metadata = file.metadata();
+    assert_eq!((metadata.atime, metadata.mtime, metadata.ctime), (1_000_000, 1_000_010, 1_000_010));
+    // only the first read after a change updates atime
+    TIME.store(1_000_020, Ordering::SeqCst);
+    let mut buffer = [0u8; 5];
+    assert_eq!(file.read_at(0, &mut buffer), 5);
+    TIME.store(1_000_030, Ordering::SeqCst);
+    assert_eq!(file.read_at(0, &mut buffer), 5);
+    assert_eq!(file.metadata().atime, 1_000_020);
+    // a new name changes ctime of the file and mtime of the directory
+    TIME.store(1_000_040, Ordering::SeqCst);
+    assert!(root_inode.link("alias", &file));
+    let metadata = file.metadata();
+    assert_eq!((metadata.mtime, metadata.ctime), (1_000_010, 1_000_040));
+    assert_eq!(root_inode.metadata().mtime, 1_000_040);
+    // setting the metadata sets ctime to now, and it all survives reopening the image
+    TIME.store(1_000_050, Ordering::SeqCst);
+    file.set_metadata(Metadata {
+        perm: 0o600,
+        uid: 1000,
+        gid: 100,
+        mtime: 12345,
+        ..file.metadata()
+    });
+    efs.lock().sync();
+    let efs = EasyFileSystem::open(block_file.clone());
+    let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+    assert_eq!(
+        file.metadata(),
+        Metadata {
+            perm: 0o600,
+            uid: 1000,
+            gid: 100,
+            atime: 1_000_020,
+            mtime: 12345,
+            ctime: 1_000_050,
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn efs_dirent_test() -> std::io::Result<()> {
+    let block_file = test_image("target/fs_dirent.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // names up to 255 bytes
+    let long_name = "n".repeat(255);
+    assert!(root_inode.create(&long_name).is_some());
+    assert!(root_inode.create(&"n".repeat(256)).is_none());
+    assert!(root_inode.create("").is_none());
+    assert_eq!(root_inode.find(&long_name).unwrap().size(), 0);
+    // a directory spanning many blocks, with names of all sorts of lengths
+    let dir = root_inode.mkdir("many").unwrap();
+    let name = |i: usize| format!("{}-{}", i, "x".repeat(i % 40));
+    for i in 0..300 {
+        assert!(dir.create(&name(i)).is_some());
+    }
+    let size = dir.size();
+    assert!(size > 10 * BLOCK_SZ as u32);
+    assert_eq!(dir.ls().len(), 300);
+    for i in 0..300 {
+        assert!(dir.find(&name(i)).is_some());
+    }
+    assert!(dir.find("300-").is_none());
+    // the room of removed dirents is reused in place
+    for i in (0..300).step_by(2) {
+        assert!(dir.unlink(&name(i)));
+    }
+    for i in (0..300).step_by(2) {
+        assert!(dir.find(&name(i)).is_none());
+        assert!(dir.create(&format!("{}-y", i)).is_some());
+    }
+    assert_eq!(dir.size(), size);
+    assert!(efs.lock().check(false).is_empty());
+    // lookups after reopening the image, when nothing is indexed yet
+    efs.lock().sync();
+    let efs = EasyFileSystem::open(block_file.clone());
+    let dir = EasyFileSystem::root_inode(&efs).find("many").unwrap();
+    for i in 0..300 {
+        let found = dir.find(&name(i)).is_some();
+        assert_eq!(found, i % 2 == 1);
+        assert_eq!(dir.find(&format!("{}-y", i)).is_some(), i % 2 == 0);
+    }
+    assert_eq!(dir.ls().len(), 300);
+    Ok(())
+}
+
+#[test]
+fn efs_rename_test() -> std::io::Result<()> {
+    let block_file = test_image("target/fs_rename.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used_inodes = || efs.lock().usage().used_inodes;
+    // within a directory
+    let file = root_inode.create("old").unwrap();
+    assert_eq!(file.write_at(0, b"data"), 4);
+    assert!(root_inode.rename("old", &root_inode, "new"));
+    assert!(root_inode.find("old").is_none());
+    assert_eq!(root_inode.find("new").unwrap().inode_id(), file.inode_id());
+    assert!(root_inode.rename("new", &root_inode, "new"));
+    assert!(!root_inode.rename("old", &root_inode, "new"));
+    // between directories, replacing a file
+    let dir = root_inode.mkdir("dir").unwrap();
+    dir.create("victim").unwrap();
+    let inodes = used_inodes();
+    assert!(root_inode.rename("new", &dir, "victim"));
+    assert_eq!(used_inodes(), inodes - 1);
+    let mut buffer = [0u8; 4];
+    assert_eq!(dir.find("victim").unwrap().read_at(0, &mut buffer), 4);
+    assert_eq!(&buffer, b"data");
+    assert_eq!(root_inode.ls(), vec!["dir"]);
+    // directories take ".." along, and cannot go under themselves
+    let sub = root_inode.mkdir("sub").unwrap();
+    assert!(root_inode.rename("sub", &dir, "sub"));
+    assert_eq!(dir.find_path("sub/..").unwrap().inode_id(), dir.inode_id());
+    assert!(!root_inode.rename("dir", &sub, "dir"));
+    assert!(!root_inode.rename("dir", &dir, "dir"));
+    // kinds must match, and only empty directories are replaced
+    assert!(!dir.rename("victim", &dir, "sub"));
+    assert!(!dir.rename("sub", &dir, "victim"));
+    root_inode.mkdir("empty").unwrap();
+    assert!(!root_inode.rename("empty", &root_inode, "dir"));
+    assert!(dir.rename("sub", &root_inode, "empty"));
+    assert_eq!(root_inode.find("empty").unwrap().inode_id(), sub.inode_id());
+    assert!(!root_inode.rename(".", &dir, "x"));
+    assert!(efs.lock().check(false).is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_read_dir_test() -> std::io::Result<()> {
+    let block_file = test_image("target/fs_read_dir.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // the dirents from an offset on, each with the offset to read the next one from
+    let read_from = |dir: &Inode, mut offset: usize| {
+        let mut dirents = Vec::new();
+        while let Some((dirent, next)) = dir.read_dir(offset) {
+            assert!(next > offset);
+            dirents.push((dirent, next));
+            offset = next;
+        }
+        dirents
+    };
+    let names = |dirents: &[(easy_fs::Dirent, usize)]| -> Vec<String> {
+        dirents.iter().map(|(dirent, _)| dirent.name.clone()).collect()
+    };
+    let dir = root_inode.mkdir("dir").unwrap();
+    let file = root_inode.create("file").unwrap();
+    let dirents = read_from(&root_inode, 0);
+    assert_eq!(names(&dirents), vec![".", "..", "dir", "file"]);
+    assert_eq!(
+        dirents[2].0,
+        easy_fs::Dirent { inode_id: dir.inode_id(), name: String::from("dir"), is_dir: true }
+    );
+    assert_eq!(dirents[3].0.inode_id, file.inode_id());
+    assert!(!dirents[3].0.is_dir);
+    assert!(file.read_dir(0).is_none());
+    // dirents spill over several blocks, and removing one keeps the offsets of the others
+    for i in 0..100 {
+        dir.create(&format!("file{}", i)).unwrap();
+    }
+    let dirents = read_from(&dir, 0);
+    assert_eq!(dirents.len(), 102);
+    assert!(dir.unlink("file10"));
+    let expected: Vec<String> = (48..100).map(|i| format!("file{}", i)).collect();
+    assert_eq!(names(&read_from(&dir, dirents[49].1)), expected);
+    Ok(())
+}
+
+/// Parse the arguments of a subcommand as given on the command line
+#[cfg(test)]
+fn subcommand_args(args: &[&str]) -> ArgMatches<'static> {
+    let command_line = std::iter::once("easy-fs-fuse").chain(args.iter().copied());
+    let matches = app().get_matches_from(command_line);
+    matches.subcommand_matches(args[0]).unwrap().clone()
+}
+
+#[test]
+fn efs_cli_test() -> std::io::Result<()> {
+    use std::io::ErrorKind;
+    let image = "target/fs_cli.img";
+    let efs = EasyFileSystem::create(test_image(image, 4096)?, 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.mkdir("dir").unwrap().create("inner").unwrap();
+    efs.lock().sync();
+    let host_dir = Path::new("target/fs_cli_host");
+    let _ = std::fs::remove_dir_all(host_dir);
+    create_dir_all(host_dir)?;
+    let small = b"hello, easy-fs".to_vec();
+    let large: Vec<u8> = (0..300 * BLOCK_SZ).map(|i| (i % 241) as u8).collect();
+    std::fs::write(host_dir.join("small"), &small)?;
+    std::fs::write(host_dir.join("large"), &large)?;
+    // add, under the host name or another path
+    easy_fs_add(&subcommand_args(&["add", image, "target/fs_cli_host/small"]))?;
+    easy_fs_add(&subcommand_args(&["add", image, "target/fs_cli_host/large", "/dir/copy"]))?;
+    let ls = |path: &str| -> std::io::Result<String> {
+        let mut out = Vec::new();
+        easy_fs_ls(&subcommand_args(&["ls", image, path]), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    };
+    let listed = ls("/")?;
+    assert!(listed.contains(&format!("{:>10} /small\n", small.len())));
+    assert!(listed.contains(&format!("{:>10} /dir/copy\n", large.len())));
+    assert!(listed.contains(&format!("{:>10} /dir/\n", "-")));
+    assert_eq!(ls("/missing").unwrap_err().kind(), ErrorKind::NotFound);
+    let mut out = Vec::new();
+    easy_fs_inspect(&subcommand_args(&["inspect", image]), &mut out)?;
+    // the root, dir, inner, small and copy
+    assert!(String::from_utf8(out).unwrap().contains("inodes: 5 / 4096 used"));
+    // extract everything, or just some of it, to get back what was added
+    let dest = host_dir.join("all");
+    easy_fs_extract(&subcommand_args(&["extract", image, dest.to_str().unwrap()]))?;
+    assert_eq!(std::fs::read(dest.join("small"))?, small);
+    assert_eq!(std::fs::read(dest.join("dir/copy"))?, large);
+    assert!(dest.join("dir/inner").is_file());
+    let dest = host_dir.join("some");
+    easy_fs_extract(&subcommand_args(&["extract", image, dest.to_str().unwrap(), "/dir/copy"]))?;
+    assert_eq!(std::fs::read(dest.join("copy"))?, large);
+    assert!(!dest.join("small").exists());
+    // failures say why
+    let long_name = format!("/{}", "n".repeat(NAME_LENGTH_LIMIT + 1));
+    let add_to = |path: &str| {
+        easy_fs_add(&subcommand_args(&["add", image, "target/fs_cli_host/small", path]))
+    };
+    assert_eq!(add_to(&long_name).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(add_to("/dir/").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(add_to("/missing/small").unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(add_to("/dir").unwrap_err().kind(), ErrorKind::AlreadyExists);
+    let rm = |path: &str| easy_fs_rm(&subcommand_args(&["rm", image, path]));
+    let error = rm("/dir").unwrap_err();
+    assert!(error.to_string().ends_with("Directory not empty"));
+    assert_eq!(rm("/dir/..").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(rm("/missing").unwrap_err().kind(), ErrorKind::NotFound);
+    // rm takes files and then the directory they leave empty
+    rm("/small")?;
+    rm("/dir/copy")?;
+    rm("/dir/inner")?;
+    rm("/dir")?;
+    assert_eq!(ls("/")?, "");
+    let efs = EasyFileSystem::open(open_image(image)?);
+    assert!(efs.lock().check(false).is_empty());
+    assert_eq!(efs.lock().usage().used_inodes, 1);
     Ok(())
 }
@@ -35,30 +35,89 @@ impl Bitmap {
     /// Allocate a new block from a block device
     pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
         for block_id in 0..self.blocks {
-            let pos = get_block_cache(
+            let block_cache = get_block_cache(
                 block_id + self.start_block_id as usize,
                 Arc::clone(block_device),
-            ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
+            );
+            let mut block_cache = block_cache.lock();
+            // only the block with a free bit is modified, so full ones stay out of the journal
+            let free = block_cache.read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block
                     .iter()
                     .enumerate()
                     .find(|(_, bits64)| **bits64 != u64::MAX)
-                    .map(|(bits64_pos, bits64)| {
-                        (bits64_pos, bits64.trailing_ones() as usize)
-                    }) {
-                    // modify cache
-                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
-                } else {
-                    None
-                }
+                    .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
             });
-            if pos.is_some() {
-                return pos;
+            if let Some((bits64_pos, inner_pos)) = free {
+                // modify cache
+                block_cache.modify(0, |bitmap_block: &mut BitmapBlock| {
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                });
+                return Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos);
             }
         }
         None
     }
+    /// Allocate a run of up to `max` contiguous bits, returns (first bit, number of bits).
+    /// The run starts at `goal` if it is free, and at the first run of `max` free bits
+    /// from `goal` on, or failing that at the first free bit, otherwise.
+    pub fn alloc_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        goal: usize,
+        max: usize,
+    ) -> Option<(usize, usize)> {
+        let goal = goal % self.maximum();
+        let first = if self.is_allocated(block_device, goal) {
+            self.find_run(block_device, goal, max)?
+        } else {
+            goal
+        };
+        let mut len = 0;
+        while len < max
+            && first + len < self.maximum()
+            && !self.is_allocated(block_device, first + len)
+        {
+            self.mark_allocated(block_device, first + len);
+            len += 1;
+        }
+        Some((first, len))
+    }
+    /// Find the first run of `len` free bits from `from` on, wrapping around,
+    /// or the first free bit if there is no such run
+    fn find_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        from: usize,
+        len: usize,
+    ) -> Option<usize> {
+        let mut first_free = None;
+        for (start, end) in [(from, self.maximum()), (0, from)] {
+            let mut run = 0;
+            let mut bitmap_block: BitmapBlock = [0; 64];
+            let mut cached_pos = usize::MAX;
+            for bit in start..end {
+                let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+                if block_pos != cached_pos {
+                    bitmap_block = get_block_cache(
+                        block_pos + self.start_block_id,
+                        Arc::clone(block_device),
+                    ).lock().read(0, |bitmap_block: &BitmapBlock| *bitmap_block);
+                    cached_pos = block_pos;
+                }
+                if bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0 {
+                    run = 0;
+                    continue;
+                }
+                first_free.get_or_insert(bit);
+                run += 1;
+                if run == len {
+                    return Some(bit + 1 - len);
+                }
+            }
+        }
+        first_free
+    }
     /// Deallocate a block
     pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
@@ -70,6 +129,40 @@ impl Bitmap {
             bitmap_block[bits64_pos] -= 1u64 << inner_pos;
         });
     }
+    /// Whether a bit is set
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device)
+        ).lock().read(0, |bitmap_block: &BitmapBlock| {
+            bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0
+        })
+    }
+    /// Set a bit which is not set yet
+    pub fn mark_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device)
+        ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+            assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) == 0);
+            bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+        });
+    }
+    /// Get the number of bits set
+    pub fn count_allocated(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        (0..self.blocks)
+            .map(|block_id| {
+                get_block_cache(
+                    block_id + self.start_block_id,
+                    Arc::clone(block_device),
+                ).lock().read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block.iter().map(|bits64| bits64.count_ones() as usize).sum::<usize>()
+                })
+            })
+            .sum()
+    }
     /// Get the max number of allocatable blocks
     pub fn maximum(&self) -> usize {
         self.blocks * BLOCK_BITS
@@ -2,8 +2,10 @@ use super::{
     BLOCK_SZ,
     BlockDevice,
 };
-use alloc::collections::VecDeque;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::*;
 use spin::Mutex;
 
@@ -17,6 +19,9 @@ pub struct BlockCache {
     block_device: Arc<dyn BlockDevice>,
     /// whether the block is dirty
     modified: bool,
+    /// whether the block has been modified by a transaction not in the journal yet,
+    /// in which case it stays in memory until the journal is flushed
+    logged: bool,
 }
 
 impl BlockCache {
@@ -32,6 +37,7 @@ impl BlockCache {
             block_id,
             block_device,
             modified: false,
+            logged: false,
         }
     }
     /// Get the address of an offset inside the cached block data
@@ -47,6 +53,14 @@ impl BlockCache {
     }
 
     pub fn get_mut<T>(&mut self, offset: usize) -> &mut T where T: Sized {
+        if in_transaction(&self.block_device) {
+            self.logged = true;
+        }
+        self.get_data_mut(offset)
+    }
+
+    /// Like `get_mut`, but for file data, which never goes through the journal
+    pub fn get_data_mut<T>(&mut self, offset: usize) -> &mut T where T: Sized {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
         self.modified = true;
@@ -62,12 +76,25 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
 
+    pub fn modify_data<T, V>(&mut self, offset:usize, f: impl FnOnce(&mut T) -> V) -> V {
+        f(self.get_data_mut(offset))
+    }
+
     pub fn sync(&mut self) {
-        if self.modified {
+        // blocks of the running transaction must reach the journal first
+        if self.modified && !self.logged {
             self.modified = false;
+            WRITEBACKS.fetch_add(1, Ordering::Relaxed);
+            count_for_device(device_id(&self.block_device), |stats| stats.writebacks += 1);
             self.block_device.write_block(self.block_id, &self.cache);
         }
     }
+
+    /// Write the block back once its transaction has been committed
+    pub fn checkpoint(&mut self) {
+        self.logged = false;
+        self.sync();
+    }
 }
 
 impl Drop for BlockCache {
@@ -76,16 +103,192 @@ impl Drop for BlockCache {
     }
 }
 
-/// Use a block cache of 16 blocks
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Default capacity of the block cache, in blocks
+pub const BLOCK_CACHE_SIZE: usize = 16;
+
+/// Marks the end of the LRU list
+const NIL: usize = usize::MAX;
+
+/// Number of dirty blocks written back to their devices so far
+static WRITEBACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Identify a block device by the address it lives at
+fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
+/// Counters describing how well the block cache is doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    /// lookups served from memory
+    pub hits: usize,
+    /// lookups that had to read the block from its device
+    pub misses: usize,
+    /// dirty blocks written back to their devices
+    pub writebacks: usize,
+}
+
+/// A cached block together with its links in the LRU list
+struct Slot {
+    device_id: usize,
+    block_id: usize,
+    cache: Arc<Mutex<BlockCache>>,
+    /// the slot used just before this one
+    prev: usize,
+    /// the slot used just after this one
+    next: usize,
+}
 
+/// An LRU cache of blocks, shared by all the block devices.
+///
+/// Lookups go through a hash table of slot indices, and the slots are
+/// chained from the least to the most recently used one, so both finding
+/// a block and picking a victim take constant time. Blocks still referenced
+/// outside of the cache are never evicted: when all of them are in use the
+/// cache grows past its capacity, and shrinks back on the following misses.
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    /// maximum number of blocks kept once they are no longer in use
+    capacity: usize,
+    /// cached blocks, indexed by the LRU links and the hash buckets
+    slots: Vec<Option<Slot>>,
+    /// indices of the unused entries of `slots`
+    free_slots: Vec<usize>,
+    /// hash buckets holding the indices of the occupied slots
+    buckets: Vec<Vec<usize>>,
+    /// least recently used slot
+    head: usize,
+    /// most recently used slot
+    tail: usize,
+    /// number of occupied slots
+    len: usize,
+    hits: usize,
+    misses: usize,
 }
 
 impl BlockCacheManager {
-    pub fn new() -> Self {
-        Self { queue: VecDeque::new() }
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "Block cache capacity must not be zero!");
+        Self {
+            capacity,
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            buckets: (0..capacity).map(|_| Vec::new()).collect(),
+            head: NIL,
+            tail: NIL,
+            len: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn bucket_of(&self, device_id: usize, block_id: usize) -> usize {
+        (device_id ^ block_id.wrapping_mul(0x9e37_79b9)) % self.buckets.len()
+    }
+
+    fn slot(&self, idx: usize) -> &Slot {
+        self.slots[idx].as_ref().unwrap()
+    }
+
+    fn slot_mut(&mut self, idx: usize) -> &mut Slot {
+        self.slots[idx].as_mut().unwrap()
+    }
+
+    /// Remove a slot from the LRU list
+    fn detach(&mut self, idx: usize) {
+        let (prev, next) = {
+            let slot = self.slot(idx);
+            (slot.prev, slot.next)
+        };
+        if prev == NIL { self.head = next; } else { self.slot_mut(prev).next = next; }
+        if next == NIL { self.tail = prev; } else { self.slot_mut(next).prev = prev; }
+    }
+
+    /// Append a slot to the LRU list as the most recently used one
+    fn attach(&mut self, idx: usize) {
+        let tail = self.tail;
+        {
+            let slot = self.slot_mut(idx);
+            slot.prev = tail;
+            slot.next = NIL;
+        }
+        if tail == NIL { self.head = idx; } else { self.slot_mut(tail).next = idx; }
+        self.tail = idx;
+    }
+
+    fn insert(&mut self, device_id: usize, block_id: usize, cache: Arc<Mutex<BlockCache>>) {
+        let slot = Slot { device_id, block_id, cache, prev: NIL, next: NIL };
+        let idx = match self.free_slots.pop() {
+            Some(idx) => {
+                self.slots[idx] = Some(slot);
+                idx
+            }
+            None => {
+                self.slots.push(Some(slot));
+                self.slots.len() - 1
+            }
+        };
+        let bucket = self.bucket_of(device_id, block_id);
+        self.buckets[bucket].push(idx);
+        self.attach(idx);
+        self.len += 1;
+    }
+
+    /// Drop a slot from the cache, writing the block back if it is dirty
+    /// and nobody else holds it
+    fn remove(&mut self, idx: usize) {
+        self.detach(idx);
+        let slot = self.slots[idx].take().unwrap();
+        let bucket = self.bucket_of(slot.device_id, slot.block_id);
+        self.buckets[bucket].retain(|&i| i != idx);
+        self.free_slots.push(idx);
+        self.len -= 1;
+    }
+
+    /// Evict the least recently used blocks that are not in use until
+    /// at most `target` blocks remain
+    fn shrink_to(&mut self, target: usize) {
+        let mut idx = self.head;
+        while self.len > target && idx != NIL {
+            let next = self.slot(idx).next;
+            let cache = &self.slot(idx).cache;
+            if Arc::strong_count(cache) == 1 && !cache.lock().logged {
+                self.remove(idx);
+            }
+            idx = next;
+        }
+    }
+
+    fn lookup(&self, device_id: usize, block_id: usize) -> Option<usize> {
+        self.buckets[self.bucket_of(device_id, block_id)]
+            .iter()
+            .copied()
+            .find(|&idx| {
+                let slot = self.slot(idx);
+                slot.device_id == device_id && slot.block_id == block_id
+            })
+    }
+
+    /// Change the number of blocks the cache keeps, evicting the
+    /// least recently used ones if it shrinks
+    pub fn set_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0, "Block cache capacity must not be zero!");
+        self.capacity = capacity;
+        self.shrink_to(capacity);
+        // rehash into one bucket per block
+        self.buckets = (0..capacity).map(|_| Vec::new()).collect();
+        for idx in 0..self.slots.len() {
+            if let Some(slot) = self.slots[idx].as_ref() {
+                let bucket = self.bucket_of(slot.device_id, slot.block_id);
+                self.buckets[bucket].push(idx);
+            }
+        }
+    }
+
+    /// Make the cache keep at least `capacity` blocks, never shrinking it
+    pub fn reserve_capacity(&mut self, capacity: usize) {
+        if capacity > self.capacity {
+            self.set_capacity(capacity);
+        }
     }
 
     pub fn get_block_cache(
@@ -93,38 +296,52 @@ impl BlockCacheManager {
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue
-            .iter()
-            .find(|pair| pair.0 == block_id) {
-                Arc::clone(&pair.1)
+        let device_id = device_id(&block_device);
+        if let Some(idx) = self.lookup(device_id, block_id) {
+            self.hits += 1;
+            count_for_device(device_id, |stats| stats.hits += 1);
+            // move it to the most recently used end
+            self.detach(idx);
+            self.attach(idx);
+            Arc::clone(&self.slot(idx).cache)
         } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self.queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1) {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
-            }
-            // load block into mem and push back
+            self.misses += 1;
+            count_for_device(device_id, |stats| stats.misses += 1);
+            // make room for the new block
+            self.shrink_to(self.capacity - 1);
+            // load block into mem and insert it
             let block_cache = Arc::new(Mutex::new(
                 BlockCache::new(block_id, Arc::clone(&block_device))
             ));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
+            self.insert(device_id, block_id, Arc::clone(&block_cache));
             block_cache
         }
     }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            writebacks: WRITEBACKS.load(Ordering::Relaxed),
+        }
+    }
 }
 
 lazy_static! {
     /// The global block cache manager
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> = Mutex::new(
-        BlockCacheManager::new()
+        BlockCacheManager::new(BLOCK_CACHE_SIZE)
     );
+    /// Ids of the block devices a transaction is running on
+    static ref TRANSACTIONS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+    /// device id -> the counters of the block cache for the blocks of that device
+    static ref DEVICE_STATS: Mutex<BTreeMap<usize, BlockCacheStats>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Update the counters of the block cache for a block device
+fn count_for_device(device_id: usize, f: impl FnOnce(&mut BlockCacheStats)) {
+    f(DEVICE_STATS.lock().entry(device_id).or_default());
 }
 
 /// Get the block cache corresponding to the given block id and block device
@@ -138,7 +355,92 @@ pub fn get_block_cache(
 /// Sync all block cache to block device
 pub fn block_cache_sync_all() {
     let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+    for slot in manager.slots.iter().flatten() {
+        slot.cache.lock().sync();
+    }
+}
+
+/// Write a block back to its device if it is cached and dirty
+pub fn block_cache_sync(block_id: usize, block_device: &Arc<dyn BlockDevice>) {
+    let cache = {
+        let manager = BLOCK_CACHE_MANAGER.lock();
+        manager
+            .lookup(device_id(block_device), block_id)
+            .map(|idx| Arc::clone(&manager.slot(idx).cache))
+    };
+    if let Some(cache) = cache {
+        cache.lock().sync();
+    }
+}
+
+/// Write all the dirty blocks of a block device back to it
+pub fn block_cache_sync_device(block_device: &Arc<dyn BlockDevice>) {
+    let device_id = device_id(block_device);
+    let caches: Vec<_> = BLOCK_CACHE_MANAGER
+        .lock()
+        .slots
+        .iter()
+        .flatten()
+        .filter(|slot| slot.device_id == device_id)
+        .map(|slot| Arc::clone(&slot.cache))
+        .collect();
+    for cache in caches {
         cache.lock().sync();
     }
 }
+
+/// Whether a transaction is running on a block device
+fn in_transaction(block_device: &Arc<dyn BlockDevice>) -> bool {
+    TRANSACTIONS.lock().contains(&device_id(block_device))
+}
+
+/// Start a transaction on a block device: the blocks modified
+/// from now on are kept in memory until it ends
+pub fn begin_transaction(block_device: &Arc<dyn BlockDevice>) {
+    let device_id = device_id(block_device);
+    let mut transactions = TRANSACTIONS.lock();
+    assert!(!transactions.contains(&device_id), "Nested transaction!");
+    transactions.push(device_id);
+}
+
+/// End the transaction running on a block device
+pub fn end_transaction(block_device: &Arc<dyn BlockDevice>) {
+    let device_id = device_id(block_device);
+    TRANSACTIONS.lock().retain(|&id| id != device_id);
+}
+
+/// Get the (block id, cache) of all the blocks of a block device
+/// modified by transactions and not checkpointed yet
+pub fn logged_blocks(
+    block_device: &Arc<dyn BlockDevice>
+) -> Vec<(usize, Arc<Mutex<BlockCache>>)> {
+    let device_id = device_id(block_device);
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .slots
+        .iter()
+        .flatten()
+        .filter(|slot| slot.device_id == device_id && slot.cache.lock().logged)
+        .map(|slot| (slot.block_id, Arc::clone(&slot.cache)))
+        .collect()
+}
+
+/// Make the block cache keep at least `capacity` blocks, never shrinking it
+pub fn reserve_block_cache_capacity(capacity: usize) {
+    BLOCK_CACHE_MANAGER.lock().reserve_capacity(capacity);
+}
+
+/// Get the hit, miss and writeback counters of the block cache
+pub fn block_cache_stats() -> BlockCacheStats {
+    BLOCK_CACHE_MANAGER.lock().stats()
+}
+
+/// Get the hit, miss and writeback counters of the block cache
+/// for the blocks of one block device alone
+pub fn block_device_cache_stats(block_device: &Arc<dyn BlockDevice>) -> BlockCacheStats {
+    DEVICE_STATS
+        .lock()
+        .get(&device_id(block_device))
+        .copied()
+        .unwrap_or_default()
+}
@@ -5,4 +5,8 @@ use core::any::Any;
 pub trait BlockDevice : Send + Sync + Any {
     fn read_block(&self, block_id: usize, buf: &mut [u8]);
     fn write_block(&self, block_id: usize, buf: &[u8]);
+    /// Get the number of blocks on the device, if it knows
+    fn num_blocks(&self) -> Option<usize> {
+        None
+    }
 }
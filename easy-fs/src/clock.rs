@@ -0,0 +1,26 @@
+//! Wall-clock time of inode timestamps, which easy-fs cannot read on its own
+
+use lazy_static::*;
+use spin::Mutex;
+
+/// A clock that is never set, which reads as the epoch
+fn no_clock() -> u64 {
+    0
+}
+
+lazy_static! {
+    /// Where the time comes from
+    static ref CLOCK: Mutex<fn() -> u64> = Mutex::new(no_clock);
+}
+
+/// Set where inode timestamps come from, a function returning seconds since the epoch,
+/// and get the clock it replaces so that it can be put back
+pub fn set_clock(clock: fn() -> u64) -> fn() -> u64 {
+    core::mem::replace(&mut *CLOCK.lock(), clock)
+}
+
+/// Get the current time in seconds since the epoch
+pub fn now() -> u64 {
+    let clock = *CLOCK.lock();
+    clock()
+}
@@ -0,0 +1,82 @@
+//! A hashed index of the dirents of recently used directories,
+//! which spares lookups from scanning whole directories
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+/// Number of directories indexed at a time
+const DIR_INDEX_CAPACITY: usize = 16;
+
+/// Hash a name with FNV-1a
+fn hash(name: &str) -> u32 {
+    name.bytes()
+        .fold(0x811c9dc5, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+/// Positions of dirents in their directories by the hash of their names
+pub struct DirIndex {
+    /// directory inode id -> name hash -> positions of the dirents
+    dirs: BTreeMap<u32, BTreeMap<u32, Vec<u32>>>,
+    /// The indexed directories, the least recently used first
+    lru: VecDeque<u32>,
+}
+
+impl DirIndex {
+    /// Create an empty index
+    pub fn new() -> Self {
+        Self {
+            dirs: BTreeMap::new(),
+            lru: VecDeque::new(),
+        }
+    }
+    /// Get the positions of the dirents which may be named `name` in a directory,
+    /// or None if the directory is not indexed
+    pub fn lookup(&mut self, dir: u32, name: &str) -> Option<Vec<u32>> {
+        let positions = self.dirs.get(&dir)?.get(&hash(name)).cloned().unwrap_or_default();
+        if let Some(i) = self.lru.iter().position(|&d| d == dir) {
+            self.lru.remove(i);
+        }
+        self.lru.push_back(dir);
+        Some(positions)
+    }
+    /// Start indexing a directory, whose dirents are to be inserted next,
+    /// dropping the least recently used directory if there are too many
+    pub fn index(&mut self, dir: u32) {
+        self.forget(dir);
+        if self.lru.len() == DIR_INDEX_CAPACITY {
+            let victim = self.lru.pop_front().unwrap();
+            self.dirs.remove(&victim);
+        }
+        self.dirs.insert(dir, BTreeMap::new());
+        self.lru.push_back(dir);
+    }
+    /// Record a dirent, if its directory is indexed
+    pub fn insert(&mut self, dir: u32, name: &str, position: u32) {
+        if let Some(names) = self.dirs.get_mut(&dir) {
+            names.entry(hash(name)).or_insert_with(Vec::new).push(position);
+        }
+    }
+    /// Forget a dirent, if its directory is indexed
+    pub fn remove(&mut self, dir: u32, name: &str, position: u32) {
+        if let Some(names) = self.dirs.get_mut(&dir) {
+            let hash = hash(name);
+            if let Some(positions) = names.get_mut(&hash) {
+                positions.retain(|&p| p != position);
+                if positions.is_empty() {
+                    names.remove(&hash);
+                }
+            }
+        }
+    }
+    /// Stop indexing a directory
+    pub fn forget(&mut self, dir: u32) {
+        if self.dirs.remove(&dir).is_some() {
+            self.lru.retain(|&d| d != dir);
+        }
+    }
+    /// Stop indexing all directories, after their dirents are changed behind the index
+    pub fn clear(&mut self) {
+        self.dirs.clear();
+        self.lru.clear();
+    }
+}
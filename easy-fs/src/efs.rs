@@ -1,28 +1,64 @@
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use spin::Mutex;
 use super::{
     BlockDevice,
+    BlockCacheStats,
     Bitmap,
     SuperBlock,
     DiskInode,
     DiskInodeType,
     Inode,
+    Journal,
+    DirIndex,
+    EFS_VERSION,
+    EFS_VERSION_EXTENTS,
+    TRANSACTION_MAX_BLOCKS,
     get_block_cache,
+    now,
     block_cache_sync_all,
+    block_cache_sync_device,
+    block_device_cache_stats,
+    reserve_block_cache_capacity,
 };
-use crate::BLOCK_SZ;
+use crate::{BLOCK_SZ, BLOCK_CACHE_SIZE};
 
 /// An easy fs over a block device
 pub struct EasyFileSystem {
     pub block_device: Arc<dyn BlockDevice>,
     pub inode_bitmap: Bitmap,
     pub data_bitmap: Bitmap,
+    journal: Journal,
     inode_area_start_block: u32,
-    data_area_start_block: u32,
+    pub(crate) data_area_start_block: u32,
+    /// Whether new files are described by extents
+    pub(crate) extents: bool,
+    /// Where the dirents of recently used directories are
+    pub(crate) dir_index: DirIndex,
+    /// inode id -> number of handles open on it, for the inodes with any
+    pub(crate) open_handles: BTreeMap<u32, usize>,
+    /// Inodes whose last name is gone while they are open,
+    /// which are released once their last handle is closed
+    pub(crate) orphans: BTreeSet<u32>,
 }
 
+/// How much of a filesystem is in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsUsage {
+    pub inodes: usize,
+    pub used_inodes: usize,
+    pub data_blocks: usize,
+    pub used_data_blocks: usize,
+}
+
+/// Number of blocks set aside for the journal
+const JOURNAL_BLOCKS: u32 = 64;
+
 /// A data block of block size
 type DataBlock = [u8; BLOCK_SZ];
+/// The raw bytes of a disk inode
+type DiskInodeBytes = [u8; core::mem::size_of::<DiskInode>()];
 
 impl EasyFileSystem {
     /// Create a filesystem from a block device
@@ -30,26 +66,42 @@ impl EasyFileSystem {
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
+    ) -> Arc<Mutex<Self>> {
+        Self::create_with_layout(block_device, total_blocks, inode_bitmap_blocks, false)
+    }
+    /// Create a filesystem from a block device, whose files are described
+    /// by extents rather than by block pointers if `extents` is set
+    pub fn create_with_layout(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        extents: bool,
     ) -> Arc<Mutex<Self>> {
         // calculate block size of areas & create bitmaps
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
+        let journal = Journal::new(1, JOURNAL_BLOCKS as usize);
+        let inode_bitmap = Bitmap::new((1 + JOURNAL_BLOCKS) as usize, inode_bitmap_blocks as usize);
         let inode_num = inode_bitmap.maximum();
         let inode_area_blocks =
             ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
-        let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
+        let inode_total_blocks = JOURNAL_BLOCKS + inode_bitmap_blocks + inode_area_blocks;
         let data_total_blocks = total_blocks - 1 - inode_total_blocks;
         let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
         let data_bitmap = Bitmap::new(
-            (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
+            (1 + inode_total_blocks) as usize,
             data_bitmap_blocks as usize,
         );
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
             inode_bitmap,
             data_bitmap,
-            inode_area_start_block: 1 + inode_bitmap_blocks,
+            journal,
+            inode_area_start_block: 1 + JOURNAL_BLOCKS + inode_bitmap_blocks,
             data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            extents,
+            dir_index: DirIndex::new(),
+            open_handles: BTreeMap::new(),
+            orphans: BTreeSet::new(),
         };
         // clear all blocks
         for i in 0..total_blocks {
@@ -72,11 +124,13 @@ impl EasyFileSystem {
                 inode_area_blocks,
                 data_bitmap_blocks,
                 data_area_blocks,
+                JOURNAL_BLOCKS,
+                if extents { EFS_VERSION_EXTENTS } else { EFS_VERSION },
             );
         });
         // write back immediately
         // create a inode for root node "/"
-        assert_eq!(efs.alloc_inode(), 0);
+        assert_eq!(efs.alloc_inode(), Some(0));
         let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
         get_block_cache(
             root_inode_block_id as usize,
@@ -84,35 +138,103 @@ impl EasyFileSystem {
         )
         .lock()
         .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-            disk_inode.initialize(DiskInodeType::Directory);
+            disk_inode.initialize(DiskInodeType::Directory, now());
         });
+        let efs = Arc::new(Mutex::new(efs));
+        // "." and ".." of the root both refer to the root itself
+        let root_inode = Self::root_inode(&efs);
+        root_inode.initialize_dir(0, &mut efs.lock());
         block_cache_sync_all();
-        Arc::new(Mutex::new(efs))
+        efs
     }
     /// Open a block device as a filesystem
     pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+        Self::open_with_cache(block_device, BLOCK_CACHE_SIZE)
+    }
+    /// Open a block device as a filesystem, keeping at least
+    /// `cache_capacity` blocks in the block cache.
+    /// The cache is shared by all the devices, so opening only ever raises
+    /// its capacity, which ends up as the largest any filesystem asked for
+    pub fn open_with_cache(
+        block_device: Arc<dyn BlockDevice>,
+        cache_capacity: usize,
+    ) -> Arc<Mutex<Self>> {
+        reserve_block_cache_capacity(cache_capacity);
         // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+        let efs = get_block_cache(0, Arc::clone(&block_device))
             .lock()
             .read(0, |super_block: &SuperBlock| {
                 assert!(super_block.is_valid(), "Error loading EFS!");
-                let inode_total_blocks =
-                    super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                let efs = Self {
+                assert!(
+                    super_block.version == EFS_VERSION
+                        || super_block.version == EFS_VERSION_EXTENTS,
+                    "Unsupported EFS layout version, the image has to be created again!"
+                );
+                let journal_blocks = super_block.journal_blocks;
+                let inode_total_blocks = journal_blocks
+                    + super_block.inode_bitmap_blocks
+                    + super_block.inode_area_blocks;
+                Self {
                     block_device,
                     inode_bitmap: Bitmap::new(
-                        1,
+                        (1 + journal_blocks) as usize,
                         super_block.inode_bitmap_blocks as usize
                     ),
                     data_bitmap: Bitmap::new(
                         (1 + inode_total_blocks) as usize,
                         super_block.data_bitmap_blocks as usize,
                     ),
-                    inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
+                    journal: Journal::new(1, journal_blocks as usize),
+                    inode_area_start_block: 1 + journal_blocks + super_block.inode_bitmap_blocks,
                     data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-                Arc::new(Mutex::new(efs))
-            })
+                    extents: super_block.version == EFS_VERSION_EXTENTS,
+                    dir_index: DirIndex::new(),
+                    open_handles: BTreeMap::new(),
+                    orphans: BTreeSet::new(),
+                }
+            });
+        // finish whatever a crash interrupted before anything else is read
+        efs.journal.replay(&efs.block_device);
+        Arc::new(Mutex::new(efs))
+    }
+    /// Start a transaction, which groups the metadata updates
+    /// that follow so that they reach the disk all or none
+    pub fn begin(&self) {
+        self.journal.begin(&self.block_device);
+    }
+    /// Commit the running transaction, which reaches the disk
+    /// with the group of transactions it joins in the journal
+    pub fn commit(&self) {
+        self.journal.commit(&self.block_device);
+    }
+    /// Write the transactions committed so far back to disk
+    pub fn flush_journal(&self) {
+        self.journal.flush(&self.block_device);
+    }
+    /// Write the transactions committed so far
+    /// and all the dirty blocks of the filesystem back to disk
+    pub fn sync(&self) {
+        self.flush_journal();
+        block_cache_sync_device(&self.block_device);
+    }
+    /// Get the counters of the block cache for the blocks of this filesystem alone
+    pub fn cache_stats(&self) -> BlockCacheStats {
+        block_device_cache_stats(&self.block_device)
+    }
+    /// Get a copy of the super block
+    pub fn super_block(&self) -> SuperBlock {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| *super_block)
+    }
+    /// Count the inodes and data blocks in use
+    pub fn usage(&self) -> FsUsage {
+        FsUsage {
+            inodes: self.inode_bitmap.maximum(),
+            used_inodes: self.inode_bitmap.count_allocated(&self.block_device),
+            data_blocks: self.super_block().data_area_blocks as usize,
+            used_data_blocks: self.data_bitmap.count_allocated(&self.block_device),
+        }
     }
     /// Get the root inode of the filesystem
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
@@ -121,12 +243,30 @@ impl EasyFileSystem {
         let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
         // release efs lock
         Inode::new(
+            0,
             block_id,
             block_offset,
             Arc::clone(efs),
             block_device,
         )
     }
+    /// Get the inode of the given number, or None if it is not in use
+    pub fn get_inode(efs: &Arc<Mutex<Self>>, inode_id: u32) -> Option<Inode> {
+        let fs = efs.lock();
+        if inode_id as usize >= fs.inode_bitmap.maximum()
+            || !fs.inode_bitmap.is_allocated(&fs.block_device, inode_id as usize)
+        {
+            return None;
+        }
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Some(Inode::new(
+            inode_id,
+            block_id,
+            block_offset,
+            Arc::clone(efs),
+            Arc::clone(&fs.block_device),
+        ))
+    }
     /// Get inode by id
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
         let inode_size = core::mem::size_of::<DiskInode>();
@@ -138,27 +278,99 @@ impl EasyFileSystem {
     pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
         self.data_area_start_block + data_block_id
     }
-    /// Allocate a new inode
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    /// Allocate a new inode, or return None if the inode area is full
+    pub fn alloc_inode(&mut self) -> Option<u32> {
+        self.inode_bitmap.alloc(&self.block_device).map(|inode_id| inode_id as u32)
     }
-    /// Allocate a data block
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// Deallocate an inode
+    /// whose data blocks must have been deallocated beforehand
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(
+            block_id as usize,
+            Arc::clone(&self.block_device)
+        )
+        .lock()
+        .modify(block_offset, |disk_inode: &mut DiskInodeBytes| {
+            disk_inode.iter_mut().for_each(|p| { *p = 0; })
+        });
+        self.inode_bitmap.dealloc(&self.block_device, inode_id as usize)
     }
-    /// Deallocate a data block
-    pub fn dealloc_data(&mut self, block_id: u32) {
+    /// Allocate a data block, which is cleared beforehand
+    pub fn alloc_data(&mut self) -> u32 {
+        let block_id =
+            self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block;
+        // the block is not referenced by anything yet,
+        // so clearing it does not need the journal
         get_block_cache(
             block_id as usize,
             Arc::clone(&self.block_device)
         )
         .lock()
-        .modify(0, |data_block: &mut DataBlock| {
+        .modify_data(0, |data_block: &mut DataBlock| {
             data_block.iter_mut().for_each(|p| { *p = 0; })
         });
+        block_id
+    }
+    /// Allocate up to `max` contiguous data blocks, which are cleared beforehand,
+    /// starting at block `goal` if it is free.
+    /// Returns (first block id, number of blocks).
+    pub fn alloc_data_run(&mut self, goal: u32, max: u32) -> (u32, u32) {
+        let goal = goal.saturating_sub(self.data_area_start_block);
+        let (bit, len) = self
+            .data_bitmap
+            .alloc_run(&self.block_device, goal as usize, max as usize)
+            .unwrap();
+        let start = bit as u32 + self.data_area_start_block;
+        for block_id in start..start + len as u32 {
+            get_block_cache(
+                block_id as usize,
+                Arc::clone(&self.block_device)
+            )
+            .lock()
+            .modify_data(0, |data_block: &mut DataBlock| {
+                data_block.iter_mut().for_each(|p| { *p = 0; })
+            });
+        }
+        (start, len as u32)
+    }
+    /// Deallocate a data block
+    pub fn dealloc_data(&mut self, block_id: u32) {
         self.data_bitmap.dealloc(
             &self.block_device,
             (block_id - self.data_area_start_block) as usize
         )
     }
+    /// Deallocate data blocks outside of a transaction, in as many transactions
+    /// as it takes for none to modify more than `TRANSACTION_MAX_BLOCKS` bitmap blocks.
+    /// A crash partway leaves the rest allocated with nothing referring to them,
+    /// which `check` reclaims.
+    pub(crate) fn dealloc_data_blocks(&mut self, mut block_ids: Vec<u32>) {
+        // blocks close to each other share their bitmap blocks
+        block_ids.sort_unstable();
+        let data_area_start_block = self.data_area_start_block;
+        let bitmap_block = |block_id: u32| {
+            (block_id - data_area_start_block) as usize / (BLOCK_SZ * 8)
+        };
+        let mut rest = &block_ids[..];
+        while !rest.is_empty() {
+            let mut bitmap_blocks = 1;
+            let mut len = 1;
+            while len < rest.len() {
+                if bitmap_block(rest[len]) != bitmap_block(rest[len - 1]) {
+                    if bitmap_blocks == TRANSACTION_MAX_BLOCKS {
+                        break;
+                    }
+                    bitmap_blocks += 1;
+                }
+                len += 1;
+            }
+            self.begin();
+            for &block_id in rest[..len].iter() {
+                self.dealloc_data(block_id);
+            }
+            self.commit();
+            rest = &rest[len..];
+        }
+    }
 }
@@ -0,0 +1,591 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter, Result};
+use super::{
+    BLOCK_SZ,
+    BlockDevice,
+    DirBlock,
+    DiskInode,
+    EasyFileSystem,
+    SuperBlock,
+    MAX_FILE_SIZE,
+    get_block_cache,
+    read_extent_block,
+};
+
+/// Number of block ids in an index block
+const INDIRECT_COUNT: usize = BLOCK_SZ / 4;
+
+/// An index block
+type IndirectBlock = [u32; INDIRECT_COUNT];
+
+/// A problem found by `EasyFileSystem::check`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// The super block is not one of easy-fs
+    BadMagic,
+    /// A field of the super block describes areas which do not fit together
+    BadSuperBlock { field: &'static str, value: u32 },
+    /// The super block describes more blocks than the device has
+    DeviceTooSmall { total_blocks: u32, device_blocks: u32 },
+    /// A dirent refers to an inode which is not in use
+    DanglingDirent { dir: u32, name: String, inode_id: u32 },
+    /// An inode in use which no dirent refers to
+    LeakedInode(u32),
+    /// The link count of an inode is not the number of dirents referring to it
+    BadLinkCount { inode_id: u32, nlink: u32, dirents: u32 },
+    /// An inode is larger than it can be, or a directory ends partway through a block
+    BadSize { inode_id: u32, size: u32 },
+    /// A block of a directory holds malformed records, which are dropped by the repair
+    BadDirBlock { dir: u32, block: u32 },
+    /// An inode refers to blocks past its size, where it has to have holes
+    BlocksPastSize { inode_id: u32, size: u32 },
+    /// An inode refers to a block outside of the data area
+    BadBlock { inode_id: u32, block_id: u32 },
+    /// An inode refers to a block which is referred to already
+    DuplicateBlock { inode_id: u32, block_id: u32 },
+    /// A block in use is free in the data bitmap
+    UnmarkedBlock(u32),
+    /// A block which nothing refers to is used in the data bitmap
+    LeakedBlock(u32),
+    /// The ".." dirent of a directory is missing,
+    /// or refers to another inode than the directory it was reached from
+    BadParent { dir: u32, parent: u32, dotdot: Option<u32> },
+}
+
+impl FsckProblem {
+    /// Whether the repair mode of `EasyFileSystem::check` fixes the problem
+    pub fn is_repairable(&self) -> bool {
+        !matches!(
+            self,
+            Self::BadMagic
+                | Self::BadSuperBlock { .. }
+                | Self::DeviceTooSmall { .. }
+                | Self::BadBlock { .. }
+                | Self::DuplicateBlock { .. }
+                | Self::BadParent { dotdot: None, .. }
+        )
+    }
+}
+
+impl Display for FsckProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::BadMagic => write!(f, "the super block is not one of easy-fs"),
+            Self::BadSuperBlock { field, value } => write!(
+                f, "the super block has a bad {} of {}", field, value
+            ),
+            Self::DeviceTooSmall { total_blocks, device_blocks } => write!(
+                f, "the super block has {} blocks but the device {}", total_blocks, device_blocks
+            ),
+            Self::DanglingDirent { dir, name, inode_id } => write!(
+                f, "dirent {:?} in directory {} refers to unused inode {}", name, dir, inode_id
+            ),
+            Self::LeakedInode(inode_id) => write!(f, "inode {} is unreachable", inode_id),
+            Self::BadLinkCount { inode_id, nlink, dirents } => write!(
+                f, "inode {} has {} links but {} dirents", inode_id, nlink, dirents
+            ),
+            Self::BadSize { inode_id, size } => write!(
+                f, "inode {} has a bad size of {} bytes", inode_id, size
+            ),
+            Self::BadDirBlock { dir, block } => write!(
+                f, "directory {} has malformed dirents in its block {}", dir, block
+            ),
+            Self::BlocksPastSize { inode_id, size } => write!(
+                f, "inode {} refers to blocks past its size of {} bytes", inode_id, size
+            ),
+            Self::BadBlock { inode_id, block_id } => write!(
+                f, "inode {} refers to block {} outside of the data area", inode_id, block_id
+            ),
+            Self::DuplicateBlock { inode_id, block_id } => write!(
+                f, "inode {} refers to block {} which is already in use", inode_id, block_id
+            ),
+            Self::UnmarkedBlock(block_id) => write!(f, "block {} is in use but free", block_id),
+            Self::LeakedBlock(block_id) => write!(f, "block {} is unreachable", block_id),
+            Self::BadParent { dir, parent, dotdot: Some(dotdot) } => write!(
+                f, "directory {} has .. referring to {} instead of {}", dir, dotdot, parent
+            ),
+            Self::BadParent { dir, dotdot: None, .. } => write!(f, "directory {} has no ..", dir),
+        }
+    }
+}
+
+/// State of a check in progress
+struct Checker<'a> {
+    fs: &'a mut EasyFileSystem,
+    repair: bool,
+    problems: Vec<FsckProblem>,
+    /// block id -> the inode referring to it
+    owners: BTreeMap<u32, u32>,
+    /// inode id -> number of dirents referring to it
+    dirents: BTreeMap<u32, u32>,
+}
+
+impl Checker<'_> {
+    /// Apply a fix in a transaction of its own, so that a repair of any size
+    /// fits in the journal and leaves the filesystem consistent fix by fix
+    fn fix(&mut self, f: impl FnOnce(&mut Self)) {
+        self.fs.begin();
+        f(self);
+        self.fs.commit();
+    }
+    fn read_disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.fs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.fs.block_device))
+            .lock()
+            .read(block_offset, f)
+    }
+    fn modify_disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.fs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.fs.block_device))
+            .lock()
+            .modify(block_offset, f)
+    }
+    /// Whether a block lies in the data area
+    fn is_data_block(&self, block_id: u32, data_area_blocks: u32) -> bool {
+        let start = self.fs.data_area_start_block;
+        block_id >= start && block_id < start + data_area_blocks
+    }
+    /// Record that an inode refers to a block,
+    /// returns whether the block can be read
+    fn claim_block(&mut self, inode_id: u32, block_id: u32, data_area_blocks: u32) -> bool {
+        // a hole claims nothing
+        if block_id == 0 {
+            return true;
+        }
+        if !self.is_data_block(block_id, data_area_blocks) {
+            self.problems.push(FsckProblem::BadBlock { inode_id, block_id });
+            return false;
+        }
+        if self.owners.insert(block_id, inode_id).is_some() {
+            self.problems.push(FsckProblem::DuplicateBlock { inode_id, block_id });
+        }
+        true
+    }
+    /// Check the size and the blocks of an inode,
+    /// returns whether all of its blocks can be read
+    fn check_inode(&mut self, inode_id: u32, data_area_blocks: u32) -> bool {
+        let (size, is_dir) = self.read_disk_inode(inode_id, |disk_inode| {
+            (disk_inode.size, disk_inode.is_dir())
+        });
+        let mut good_size = size.min(MAX_FILE_SIZE);
+        if is_dir {
+            good_size -= good_size % BLOCK_SZ as u32;
+        }
+        if good_size != size {
+            self.problems.push(FsckProblem::BadSize { inode_id, size });
+            if self.repair {
+                self.fix(|checker| {
+                    checker.modify_disk_inode(inode_id, |disk_inode| disk_inode.size = good_size);
+                });
+            }
+        }
+        let problems = self.problems.len();
+        let readable = if self.read_disk_inode(inode_id, |disk_inode| disk_inode.uses_extents()) {
+            self.check_extents(inode_id, good_size, data_area_blocks)
+        } else {
+            self.check_tree(inode_id, good_size, data_area_blocks)
+        };
+        // what lies past the size can only be looked into once the rest is found sound,
+        // the blocks left there are not claimed and so are freed as leaked blocks
+        if self.problems.len() == problems && self.has_blocks_past_size(inode_id, good_size) {
+            self.problems.push(FsckProblem::BlocksPastSize { inode_id, size: good_size });
+            if self.repair {
+                self.fix(|checker| {
+                    let block_device = Arc::clone(&checker.fs.block_device);
+                    let unused = checker.modify_disk_inode(inode_id, |disk_inode| {
+                        disk_inode.clear_blocks_past_size(&block_device)
+                    });
+                    for block_id in unused {
+                        checker.owners.remove(&block_id);
+                    }
+                });
+            }
+        }
+        readable
+    }
+    /// Check the block tree of an inode and the data blocks in it up to `size`,
+    /// without following bad index blocks.
+    /// Returns whether all of them can be read.
+    fn check_tree(&mut self, inode_id: u32, size: u32, data_area_blocks: u32) -> bool {
+        let (direct, indirect) = self.read_disk_inode(inode_id, |disk_inode| {
+            (disk_inode.direct, [disk_inode.indirect1, disk_inode.indirect2, disk_inode.indirect3])
+        });
+        let mut data_blocks = ((size as usize) + BLOCK_SZ - 1) / BLOCK_SZ;
+        let mut readable = true;
+        for &block_id in direct.iter().take(data_blocks) {
+            readable &= self.claim_block(inode_id, block_id, data_area_blocks);
+        }
+        data_blocks = data_blocks.saturating_sub(direct.len());
+        for (level, &index_block) in indirect.iter().enumerate() {
+            if data_blocks == 0 {
+                break;
+            }
+            let span = INDIRECT_COUNT.pow(level as u32 + 1);
+            readable &= self.check_index_block(
+                inode_id,
+                index_block,
+                level + 1,
+                data_blocks.min(span),
+                data_area_blocks,
+            );
+            data_blocks = data_blocks.saturating_sub(span);
+        }
+        readable
+    }
+    /// Check the extents of an inode and the data blocks in them up to its size,
+    /// without following bad extent blocks.
+    /// Returns whether all of them can be read.
+    fn check_extents(&mut self, inode_id: u32, size: u32, data_area_blocks: u32) -> bool {
+        let (direct, mut block_id) = self.read_disk_inode(inode_id, |disk_inode| {
+            (disk_inode.direct, disk_inode.indirect1)
+        });
+        let mut extents: Vec<(u32, u32)> = direct
+            .chunks(2)
+            .take_while(|pair| pair[1] != 0)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+        let mut readable = true;
+        // a chain longer than the data area must loop
+        for _ in 0..data_area_blocks {
+            if block_id == 0 {
+                break;
+            }
+            if !self.claim_block(inode_id, block_id, data_area_blocks) {
+                readable = false;
+                break;
+            }
+            let (next, more) = read_extent_block(block_id, &self.fs.block_device);
+            extents.extend(more);
+            block_id = next;
+        }
+        let mut data_blocks = (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32;
+        for (first, len) in extents {
+            let len = len.min(data_blocks);
+            if first != 0 {
+                for block_id in first..first.saturating_add(len) {
+                    readable &= self.claim_block(inode_id, block_id, data_area_blocks);
+                }
+            }
+            data_blocks -= len;
+        }
+        readable
+    }
+    /// Check an index block of the given level, 1 for one referring to data blocks,
+    /// and the first `count` data blocks under it.
+    /// Returns whether all of them can be read.
+    fn check_index_block(
+        &mut self,
+        inode_id: u32,
+        block_id: u32,
+        level: usize,
+        count: usize,
+        data_area_blocks: u32,
+    ) -> bool {
+        // a hole needs no checking
+        if block_id == 0 {
+            return true;
+        }
+        if !self.claim_block(inode_id, block_id, data_area_blocks) {
+            return false;
+        }
+        let span = INDIRECT_COUNT.pow(level as u32 - 1);
+        let entries = (count + span - 1) / span;
+        let mut readable = true;
+        for (i, entry) in self.read_index_block(block_id, entries).into_iter().enumerate() {
+            readable &= if level == 1 {
+                self.claim_block(inode_id, entry, data_area_blocks)
+            } else {
+                let count = (count - i * span).min(span);
+                self.check_index_block(inode_id, entry, level - 1, count, data_area_blocks)
+            };
+        }
+        readable
+    }
+    /// Whether an inode refers to blocks past `size`,
+    /// reading only the index and extent blocks which lead to blocks up to it
+    fn has_blocks_past_size(&self, inode_id: u32, size: u32) -> bool {
+        let block_device = Arc::clone(&self.fs.block_device);
+        let data_blocks = ((size as usize) + BLOCK_SZ - 1) / BLOCK_SZ;
+        let (extents, direct, indirect) = self.read_disk_inode(inode_id, |disk_inode| {
+            let extents = disk_inode.uses_extents().then(|| disk_inode.extents(&block_device));
+            let indirect = [disk_inode.indirect1, disk_inode.indirect2, disk_inode.indirect3];
+            (extents, disk_inode.direct, indirect)
+        });
+        if let Some(extents) = extents {
+            let mut pos = 0usize;
+            return extents.into_iter().any(|(first, len)| {
+                pos += len as usize;
+                first != 0 && pos > data_blocks
+            });
+        }
+        if direct.iter().skip(data_blocks).any(|&block_id| block_id != 0) {
+            return true;
+        }
+        let mut data_blocks = data_blocks.saturating_sub(direct.len());
+        for (level, &index_block) in indirect.iter().enumerate() {
+            let span = INDIRECT_COUNT.pow(level as u32 + 1);
+            if index_block != 0
+                && data_blocks < span
+                && self.index_block_past(index_block, level + 1, data_blocks)
+            {
+                return true;
+            }
+            data_blocks = data_blocks.saturating_sub(span);
+        }
+        false
+    }
+    /// Whether an index block of the given level refers to blocks
+    /// past its first `count` data blocks
+    fn index_block_past(&self, block_id: u32, level: usize, count: usize) -> bool {
+        if count == 0 {
+            return true;
+        }
+        let span = INDIRECT_COUNT.pow(level as u32 - 1);
+        let entries = self.read_index_block(block_id, INDIRECT_COUNT);
+        let kept = (count + span - 1) / span;
+        if entries[kept..].iter().any(|&entry| entry != 0) {
+            return true;
+        }
+        // the last entry kept may lead to blocks past the count itself
+        let last = entries[kept - 1];
+        level > 1 && last != 0 && count % span != 0
+            && self.index_block_past(last, level - 1, count % span)
+    }
+    /// Get the first block ids of an index block
+    fn read_index_block(&self, block_id: u32, count: usize) -> Vec<u32> {
+        get_block_cache(block_id as usize, Arc::clone(&self.fs.block_device))
+            .lock()
+            .read(0, |index_block: &IndirectBlock| index_block[..count].to_vec())
+    }
+    /// Check the dirents of a directory reached from `parent`,
+    /// returns the inodes reached for the first time
+    fn check_dir(&mut self, dir: u32, parent: u32) -> Vec<u32> {
+        let block_device = Arc::clone(&self.fs.block_device);
+        let inode_num = self.fs.inode_bitmap.maximum() as u32;
+        let blocks = self.read_disk_inode(dir, |disk_inode| disk_inode.size as usize / BLOCK_SZ);
+        let mut reached = Vec::new();
+        let mut has_dotdot = false;
+        for block in 0..blocks {
+            let mut dir_block = DirBlock::empty();
+            self.read_disk_inode(dir, |disk_inode| {
+                disk_inode.read_at(block * BLOCK_SZ, dir_block.as_bytes_mut(), &block_device)
+            });
+            let mut changed = false;
+            if !dir_block.is_valid() {
+                self.problems.push(FsckProblem::BadDirBlock { dir, block: block as u32 });
+                dir_block.repair();
+                changed = true;
+            }
+            let mut dangling = Vec::new();
+            let mut bad_dotdot = None;
+            for dirent in dir_block.entries() {
+                if dirent.name == ".." {
+                    has_dotdot = true;
+                    if dirent.inode_number != parent {
+                        self.problems.push(FsckProblem::BadParent {
+                            dir,
+                            parent,
+                            dotdot: Some(dirent.inode_number),
+                        });
+                        bad_dotdot = Some(dirent.offset);
+                    }
+                }
+                if dirent.name == "." || dirent.name == ".." {
+                    continue;
+                }
+                let inode_id = dirent.inode_number;
+                let in_use = inode_id < inode_num
+                    && self.fs.inode_bitmap.is_allocated(&block_device, inode_id as usize)
+                    && self.read_disk_inode(inode_id, |disk_inode| disk_inode.nlink > 0);
+                if !in_use {
+                    self.problems.push(FsckProblem::DanglingDirent {
+                        dir,
+                        name: String::from(dirent.name),
+                        inode_id,
+                    });
+                    dangling.push(dirent.offset);
+                    continue;
+                }
+                let count = self.dirents.entry(inode_id).or_insert(0);
+                *count += 1;
+                if *count == 1 {
+                    reached.push(inode_id);
+                }
+            }
+            for offset in dangling {
+                dir_block.remove(offset);
+                changed = true;
+            }
+            if let Some(offset) = bad_dotdot {
+                dir_block.set_inode(offset, parent);
+                changed = true;
+            }
+            if self.repair && changed {
+                self.fix(|checker| {
+                    checker.modify_disk_inode(dir, |disk_inode| {
+                        disk_inode.write_at(block * BLOCK_SZ, dir_block.as_bytes(), &block_device)
+                    });
+                });
+            }
+        }
+        if !has_dotdot {
+            self.problems.push(FsckProblem::BadParent { dir, parent, dotdot: None });
+        }
+        reached
+    }
+}
+
+impl EasyFileSystem {
+    /// Check that the super block of a block device describes areas
+    /// which fit together and fit on the device.
+    /// Nothing else can be checked, or even opened, if any problem is found.
+    pub fn check_super_block(block_device: &Arc<dyn BlockDevice>) -> Vec<FsckProblem> {
+        let super_block = get_block_cache(0, Arc::clone(block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| *super_block);
+        if !super_block.is_valid() {
+            return vec![FsckProblem::BadMagic];
+        }
+        let mut problems = Vec::new();
+        let inodes = super_block.inode_bitmap_blocks as usize * BLOCK_SZ * 8;
+        let inode_bytes = inodes * core::mem::size_of::<DiskInode>();
+        if (super_block.inode_area_blocks as usize) < (inode_bytes + BLOCK_SZ - 1) / BLOCK_SZ {
+            problems.push(FsckProblem::BadSuperBlock {
+                field: "inode_area_blocks",
+                value: super_block.inode_area_blocks,
+            });
+        }
+        if (super_block.data_bitmap_blocks as usize) * BLOCK_SZ * 8
+            < super_block.data_area_blocks as usize
+        {
+            problems.push(FsckProblem::BadSuperBlock {
+                field: "data_bitmap_blocks",
+                value: super_block.data_bitmap_blocks,
+            });
+        }
+        // a journal needs room for its header
+        if super_block.journal_blocks == 1 {
+            problems.push(FsckProblem::BadSuperBlock {
+                field: "journal_blocks",
+                value: super_block.journal_blocks,
+            });
+        }
+        let area_blocks = [
+            super_block.journal_blocks,
+            super_block.inode_bitmap_blocks,
+            super_block.inode_area_blocks,
+            super_block.data_bitmap_blocks,
+            super_block.data_area_blocks,
+        ]
+        .iter()
+        .try_fold(1u32, |sum, &blocks| sum.checked_add(blocks));
+        if area_blocks != Some(super_block.total_blocks) {
+            problems.push(FsckProblem::BadSuperBlock {
+                field: "total_blocks",
+                value: super_block.total_blocks,
+            });
+        }
+        if let Some(device_blocks) = block_device.num_blocks() {
+            if super_block.total_blocks as usize > device_blocks {
+                problems.push(FsckProblem::DeviceTooSmall {
+                    total_blocks: super_block.total_blocks,
+                    device_blocks: device_blocks as u32,
+                });
+            }
+        }
+        problems
+    }
+    /// Check that the super block fits the device, and that the bitmaps,
+    /// the inodes and the dirents agree with each other,
+    /// and fix what can be fixed when `repair` is set.
+    /// Returns the problems found.
+    pub fn check(&mut self, repair: bool) -> Vec<FsckProblem> {
+        let block_device = Arc::clone(&self.block_device);
+        let problems = Self::check_super_block(&block_device);
+        if !problems.is_empty() {
+            return problems;
+        }
+        let data_area_blocks = self.super_block().data_area_blocks;
+        let mut checker = Checker {
+            fs: self,
+            repair,
+            problems: Vec::new(),
+            owners: BTreeMap::new(),
+            dirents: BTreeMap::new(),
+        };
+        // walk the tree from the root, which is its own parent
+        checker.dirents.insert(0, 1);
+        let mut queue = VecDeque::new();
+        queue.push_back((0, 0));
+        while let Some((inode_id, parent)) = queue.pop_front() {
+            let readable = checker.check_inode(inode_id, data_area_blocks);
+            // the dirents of a directory with bad blocks cannot be trusted
+            if readable && checker.read_disk_inode(inode_id, |disk_inode| disk_inode.is_dir()) {
+                let reached = checker.check_dir(inode_id, parent);
+                queue.extend(reached.into_iter().map(|child| (child, inode_id)));
+            }
+        }
+        // inodes open after their last name is gone are in use, though unreachable
+        let orphans: Vec<u32> = checker.fs.orphans.iter().copied().collect();
+        for &inode_id in orphans.iter() {
+            checker.check_inode(inode_id, data_area_blocks);
+        }
+        // link counts
+        let dirents: Vec<(u32, u32)> = checker.dirents.iter().map(|(&k, &v)| (k, v)).collect();
+        for (inode_id, dirents) in dirents {
+            let nlink = checker.read_disk_inode(inode_id, |disk_inode| disk_inode.nlink);
+            if nlink != dirents {
+                checker.problems.push(FsckProblem::BadLinkCount { inode_id, nlink, dirents });
+                if repair {
+                    checker.fix(|checker| {
+                        checker.modify_disk_inode(inode_id, |disk_inode| disk_inode.nlink = dirents);
+                    });
+                }
+            }
+        }
+        // inodes in use that were not reached
+        for inode_id in 0..checker.fs.inode_bitmap.maximum() as u32 {
+            if checker.fs.inode_bitmap.is_allocated(&block_device, inode_id as usize)
+                && !checker.dirents.contains_key(&inode_id)
+                && !orphans.contains(&inode_id)
+            {
+                checker.problems.push(FsckProblem::LeakedInode(inode_id));
+                // the blocks of the inode are freed as leaked blocks below
+                if repair {
+                    checker.fix(|checker| checker.fs.dealloc_inode(inode_id));
+                }
+            }
+        }
+        // the data bitmap against the blocks in use
+        for bit in 0..data_area_blocks {
+            let block_id = checker.fs.data_area_start_block + bit;
+            let in_use = checker.owners.contains_key(&block_id);
+            let allocated = checker.fs.data_bitmap.is_allocated(&block_device, bit as usize);
+            if in_use && !allocated {
+                checker.problems.push(FsckProblem::UnmarkedBlock(block_id));
+                if repair {
+                    checker.fix(|checker| {
+                        checker.fs.data_bitmap.mark_allocated(&block_device, bit as usize);
+                    });
+                }
+            } else if !in_use && allocated {
+                checker.problems.push(FsckProblem::LeakedBlock(block_id));
+                if repair {
+                    checker.fix(|checker| {
+                        checker.fs.data_bitmap.dealloc(&block_device, bit as usize);
+                    });
+                }
+            }
+        }
+        let problems = checker.problems;
+        if repair {
+            // the dirents may have moved behind the index
+            self.dir_index.clear();
+        }
+        problems
+    }
+}
@@ -0,0 +1,140 @@
+use alloc::sync::Arc;
+use super::{
+    BlockDevice,
+    BLOCK_SZ,
+    JournalHeader,
+    JOURNAL_TARGET_COUNT,
+    get_block_cache,
+    begin_transaction,
+    end_transaction,
+    logged_blocks,
+};
+
+/// A data block
+type DataBlock = [u8; BLOCK_SZ];
+
+/// Most blocks a single transaction may modify.
+/// Operations too large for that are split into several transactions.
+pub const TRANSACTION_MAX_BLOCKS: usize = 32;
+
+/// A write-ahead journal of metadata blocks.
+///
+/// Metadata blocks modified during a transaction stay in the block cache
+/// after it commits, grouped with those of the transactions committed after it.
+/// The group is flushed once the next transaction might not fit in the journal
+/// anymore, or when the filesystem is synced: the images of its blocks are
+/// written to the journal, the journal header is written as the commit record,
+/// and only after that do the blocks go to their own place on disk.
+/// A crash at any point loses the group or leaves a committed one
+/// that `replay` finishes, so each transaction reaches the disk all or none.
+pub struct Journal {
+    start_block_id: usize,
+    blocks: usize,
+}
+
+impl Journal {
+    /// A new journal from start block id and number of blocks,
+    /// journaling is disabled when there are no blocks
+    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+        assert!(blocks != 1, "A journal needs room for its header!");
+        Self {
+            start_block_id,
+            blocks,
+        }
+    }
+    /// Get the max number of blocks logged at once
+    fn capacity(&self) -> usize {
+        (self.blocks - 1).min(JOURNAL_TARGET_COUNT)
+    }
+    /// Start a transaction
+    pub fn begin(&self, block_device: &Arc<dyn BlockDevice>) {
+        if self.blocks > 0 {
+            begin_transaction(block_device);
+        }
+    }
+    /// Commit the running transaction into the current group,
+    /// flushing the group if the next transaction might not fit in with it
+    pub fn commit(&self, block_device: &Arc<dyn BlockDevice>) {
+        if self.blocks == 0 {
+            return;
+        }
+        end_transaction(block_device);
+        let logged = logged_blocks(block_device).len();
+        assert!(logged <= self.capacity(), "Transaction too large for the journal!");
+        if logged + TRANSACTION_MAX_BLOCKS > self.capacity() {
+            self.flush(block_device);
+        }
+    }
+    /// Write the group of committed transactions through the journal to disk
+    pub fn flush(&self, block_device: &Arc<dyn BlockDevice>) {
+        if self.blocks == 0 {
+            return;
+        }
+        let blocks = logged_blocks(block_device);
+        if blocks.is_empty() {
+            return;
+        }
+        let mut targets = [0u32; JOURNAL_TARGET_COUNT];
+        // log the blocks
+        for (i, (block_id, cache)) in blocks.iter().enumerate() {
+            let data = cache.lock().read(0, |data_block: &DataBlock| *data_block);
+            let log_block = get_block_cache(
+                self.start_block_id + 1 + i,
+                Arc::clone(block_device)
+            );
+            let mut log_block = log_block.lock();
+            log_block.modify(0, |data_block: &mut DataBlock| {
+                data_block.copy_from_slice(&data);
+            });
+            log_block.sync();
+            targets[i] = *block_id as u32;
+        }
+        // commit
+        self.modify_header(block_device, |header| header.commit(&targets[..blocks.len()]));
+        // checkpoint
+        for (_, cache) in blocks.iter() {
+            cache.lock().checkpoint();
+        }
+        self.modify_header(block_device, |header| header.clear());
+    }
+    /// Finish the transaction left in the journal by a crash, if any
+    pub fn replay(&self, block_device: &Arc<dyn BlockDevice>) {
+        if self.blocks == 0 {
+            return;
+        }
+        let mut targets = [0u32; JOURNAL_TARGET_COUNT];
+        let count = get_block_cache(self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .read(0, |header: &JournalHeader| {
+                let committed = header.committed();
+                targets[..committed.len()].copy_from_slice(committed);
+                committed.len()
+            });
+        for (i, &target) in targets[..count].iter().enumerate() {
+            let data = get_block_cache(
+                self.start_block_id + 1 + i,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .read(0, |data_block: &DataBlock| *data_block);
+            let target = get_block_cache(target as usize, Arc::clone(block_device));
+            let mut target = target.lock();
+            target.modify(0, |data_block: &mut DataBlock| {
+                data_block.copy_from_slice(&data);
+            });
+            target.sync();
+        }
+        self.modify_header(block_device, |header| header.clear());
+    }
+    /// Modify the journal header and write it to disk at once
+    fn modify_header(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        f: impl FnOnce(&mut JournalHeader),
+    ) {
+        let header = get_block_cache(self.start_block_id, Arc::clone(block_device));
+        let mut header = header.lock();
+        header.modify(0, f);
+        header.sync();
+    }
+}
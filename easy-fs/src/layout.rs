@@ -9,24 +9,45 @@ use alloc::vec::Vec;
 
 /// Magic number for sanity check
 const EFS_MAGIC: u32 = 0x3b800001;
+/// Version of the on-disk layout, images from before it was recorded read as 0.
+/// Version 2 trades a direct block of each inode for a triple-indirect one,
+/// version 4 trades more of them for timestamps, permissions and owners,
+/// version 6 replaces 32-byte dirents with records of variable length.
+pub const EFS_VERSION: u32 = 6;
+/// Version 6 with files described by extents
+pub const EFS_VERSION_EXTENTS: u32 = 7;
+/// Magic number of a journal holding a committed transaction
+const JOURNAL_MAGIC: u32 = 0x6a726e6c;
+/// The max number of blocks described by a journal header
+pub const JOURNAL_TARGET_COUNT: usize = BLOCK_SZ / 4 - 2;
 /// The max number of direct inodes
-const INODE_DIRECT_COUNT: usize = 28;
+const INODE_DIRECT_COUNT: usize = 18;
 /// The max length of inode name
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 255;
+/// The max number of extents held in a disk inode, in place of its direct blocks
+const INODE_EXTENT_COUNT: usize = INODE_DIRECT_COUNT / 2;
+/// The number of extents in an extent block, which starts with the next extent block
+const EXTENT_BLOCK_COUNT: usize = BLOCK_SZ / 8 - 1;
 /// The max number of indirect1 inodes
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 /// The max number of indirect2 inodes
 const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
+/// The max number of indirect3 inodes
+const INODE_INDIRECT3_COUNT: usize = INODE_INDIRECT2_COUNT * INODE_INDIRECT1_COUNT;
 /// The upper bound of direct inode index
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 /// The upper bound of indirect1 inode index
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 /// The upper bound of indirect2 inode index
-#[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// The upper bound of indirect3 inode index
+const INDIRECT3_BOUND: usize = INDIRECT2_BOUND + INODE_INDIRECT3_COUNT;
+/// The max size of a file in bytes, about 1 GiB
+pub const MAX_FILE_SIZE: u32 = (INDIRECT3_BOUND * BLOCK_SZ) as u32;
 
 /// Super block of a filesystem
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct SuperBlock {
     magic: u32,
     pub total_blocks: u32,
@@ -34,6 +55,10 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// Blocks of the journal, which sits right after the super block
+    pub journal_blocks: u32,
+    /// Version of the layout
+    pub version: u32,
 }
 
 impl Debug for SuperBlock {
@@ -44,12 +69,15 @@ impl Debug for SuperBlock {
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("journal_blocks", &self.journal_blocks)
+            .field("version", &self.version)
             .finish()
     }
 }
 
 impl SuperBlock {
     /// Initialize a super block
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize(
         &mut self,
         total_blocks: u32,
@@ -57,6 +85,8 @@ impl SuperBlock {
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
         data_area_blocks: u32,
+        journal_blocks: u32,
+        version: u32,
     ) {
         *self = Self {
             magic: EFS_MAGIC,
@@ -65,6 +95,8 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            journal_blocks,
+            version,
         }
     }
     /// Check if a super block is valid using efs magic
@@ -73,6 +105,40 @@ impl SuperBlock {
     }
 }
 
+/// First block of the journal, which also serves as its commit record:
+/// the logged blocks following it are only replayed once it is written
+#[repr(C)]
+pub struct JournalHeader {
+    magic: u32,
+    /// number of logged blocks
+    count: u32,
+    /// where each of the logged blocks belongs
+    targets: [u32; JOURNAL_TARGET_COUNT],
+}
+
+impl JournalHeader {
+    /// Commit a transaction made of blocks logged for the given targets
+    pub fn commit(&mut self, targets: &[u32]) {
+        assert!(targets.len() <= JOURNAL_TARGET_COUNT);
+        self.magic = JOURNAL_MAGIC;
+        self.count = targets.len() as u32;
+        self.targets[..targets.len()].copy_from_slice(targets);
+    }
+    /// Mark the journal as empty
+    pub fn clear(&mut self) {
+        self.magic = 0;
+        self.count = 0;
+    }
+    /// Get the targets of the committed transaction, if any
+    pub fn committed(&self) -> &[u32] {
+        if self.magic == JOURNAL_MAGIC {
+            &self.targets[..self.count as usize]
+        } else {
+            &[]
+        }
+    }
+}
+
 /// Type of a disk inode
 #[derive(PartialEq)]
 pub enum DiskInodeType {
@@ -92,18 +158,61 @@ pub struct DiskInode {
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
+    pub indirect3: u32,
+    /// Number of dirents referring to this inode
+    pub nlink: u32,
+    /// User id of the owner
+    pub uid: u32,
+    /// Group id of the owner
+    pub gid: u32,
+    /// Permission bits, rwx for the owner, the group and others
+    pub perm: u16,
     type_: DiskInodeType,
+    /// Whether the data blocks are described by extents rather than by block pointers,
+    /// which takes what used to be padding, so older inodes read as not using extents.
+    /// Extents are (first block id, number of blocks) pairs in file order, held in place
+    /// of the direct blocks and then in a chain of extent blocks starting at indirect1.
+    extents: bool,
+    /// Time of last access, in seconds since the epoch
+    pub atime: u64,
+    /// Time of last modification of the data
+    pub mtime: u64,
+    /// Time of last change of the data or the metadata
+    pub ctime: u64,
 }
 
 impl DiskInode {
-    /// Initialize a disk inode, as well as all direct inodes under it
-    /// indirect1 and indirect2 block are allocated only when they are needed
-    pub fn initialize(&mut self, type_: DiskInodeType) {
+    /// Initialize a disk inode created at `now`, as well as all direct inodes under it
+    /// indirect1, indirect2 and indirect3 block are allocated only when they are needed
+    pub fn initialize(&mut self, type_: DiskInodeType, now: u64) {
         self.size = 0;
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        self.indirect3 = 0;
+        self.nlink = 1;
+        self.uid = 0;
+        self.gid = 0;
+        self.perm = if type_ == DiskInodeType::Directory { 0o755 } else { 0o644 };
         self.type_ = type_;
+        self.extents = false;
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
+    }
+    /// Record a change of the data at `now`
+    pub fn set_modified(&mut self, now: u64) {
+        self.mtime = now;
+        self.ctime = now;
+    }
+    /// Describe the data blocks of current disk inode, which must be empty, by extents
+    pub fn use_extents(&mut self) {
+        assert!(self.size == 0);
+        self.extents = true;
+    }
+    /// Whether the data blocks are described by extents
+    pub fn uses_extents(&self) -> bool {
+        self.extents
     }
     /// Whether this inode is a directory
     pub fn is_dir(&self) -> bool {
@@ -115,234 +224,335 @@ impl DiskInode {
         self.type_ == DiskInodeType::File
     }
     /// Get the number of data blocks corresponding to size
-    pub fn data_blocks(&self) -> u32 {
-        Self::_data_blocks(self.size)
-    }
     fn _data_blocks(size: u32) -> u32 {
         (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
     }
-    /// Get the number of data blocks required for the given size of data
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
-        // indirect1
-        if data_blocks > INODE_DIRECT_COUNT {
-            total += 1;
+    /// Get the index block of the given level, 1 for indirect1 and so on
+    fn indirect(&mut self, level: usize) -> &mut u32 {
+        match level {
+            1 => &mut self.indirect1,
+            2 => &mut self.indirect2,
+            _ => &mut self.indirect3,
         }
-        // indirect2
-        if data_blocks > INDIRECT1_BOUND {
-            total += 1;
-            // sub indirect1
-            total += (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
-        }
-        total as u32
     }
-    /// Get the number of data blocks that have to be allocated given the new size of data
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+    /// Locate a block past the direct ones given inner id,
+    /// returns (level of the index block above it, inner id under that index block)
+    fn locate(inner_id: usize) -> (usize, usize) {
+        if inner_id < INDIRECT1_BOUND {
+            (1, inner_id - DIRECT_BOUND)
+        } else if inner_id < INDIRECT2_BOUND {
+            (2, inner_id - INDIRECT1_BOUND)
+        } else {
+            assert!(inner_id < INDIRECT3_BOUND, "File too large!");
+            (3, inner_id - INDIRECT2_BOUND)
+        }
     }
-    /// Get id of block given inner id
+    /// Get id of block given inner id, or 0 if the block is a hole
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        if self.extents {
+            let mut pos = 0;
+            for (first, len) in self.extents(block_device) {
+                if inner_id < pos + len {
+                    return if first == 0 { 0 } else { first + inner_id - pos };
+                }
+                pos += len;
+            }
+            return 0;
+        }
         let inner_id = inner_id as usize;
         if inner_id < INODE_DIRECT_COUNT {
-            self.direct[inner_id]
-        } else if inner_id < INDIRECT1_BOUND {
-            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-                .lock()
-                .read(0, |indirect_block: &IndirectBlock| {
-                    indirect_block[inner_id - INODE_DIRECT_COUNT]
-                })
-        } else {
-            let last = inner_id - INDIRECT1_BOUND;
-            let indirect1 = get_block_cache(
-                self.indirect2 as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .read(0, |indirect2: &IndirectBlock| {
-                indirect2[last / INODE_INDIRECT1_COUNT]
-            });
-            get_block_cache(
-                indirect1 as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .read(0, |indirect1: &IndirectBlock| {
-                indirect1[last % INODE_INDIRECT1_COUNT]
-            })
+            return self.direct[inner_id];
+        }
+        let (level, mut inner_id) = Self::locate(inner_id);
+        let mut block_id = [self.indirect1, self.indirect2, self.indirect3][level - 1];
+        for level in (0..level).rev() {
+            let span = INODE_INDIRECT1_COUNT.pow(level as u32);
+            block_id = read_index(block_id, inner_id / span, block_device);
+            inner_id %= span;
         }
+        block_id
     }
-    /// Inncrease the size of current disk inode
-    pub fn increase_size(
+    /// Fill the holes among the blocks of inner id [start, end) with blocks from `alloc`,
+    /// which takes (goal block id, max number of blocks) and returns a run of blocks
+    /// as (first block id, number of blocks), starting at the goal if it can.
+    /// Returns the extent blocks which are not needed anymore.
+    pub fn alloc_blocks(
         &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,
+        start: u32,
+        end: u32,
+        alloc: &mut impl FnMut(u32, u32) -> (u32, u32),
         block_device: &Arc<dyn BlockDevice>,
-    ) {
-        let mut current_blocks = self.data_blocks();
-        self.size = new_size;
-        let mut total_blocks = self.data_blocks();
-        let mut new_blocks = new_blocks.into_iter();
-        // fill direct
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
-        }
-        // alloc indirect1
-        if total_blocks > INODE_DIRECT_COUNT as u32{
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                self.indirect1 = new_blocks.next().unwrap();
+    ) -> Vec<u32> {
+        if !self.extents {
+            for inner_id in start..end {
+                self.alloc_block_id(inner_id, &mut || alloc(0, 1).0, block_device);
             }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
-        } else {
-            return;
+            return Vec::new();
         }
-        // fill indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                current_blocks += 1;
+        let mut extents = self.extents(block_device);
+        let mapped: u32 = extents.iter().map(|&(_, len)| len).sum();
+        if mapped < end {
+            extents.push((0, end - mapped));
+        }
+        let mut v: Vec<(u32, u32)> = Vec::new();
+        let mut pos = 0;
+        for (first, len) in extents {
+            let (hole_start, hole_end) = (pos.max(start), (pos + len).min(end));
+            if first != 0 || hole_start >= hole_end {
+                push_extent(&mut v, first, len);
+            } else {
+                push_extent(&mut v, 0, hole_start - pos);
+                let mut inner_id = hole_start;
+                while inner_id < hole_end {
+                    // carry on from the end of the previous extent if possible
+                    let goal = match v.last() {
+                        Some(&(first, len)) if first != 0 => first + len,
+                        _ => 0,
+                    };
+                    let (first, len) = alloc(goal, hole_end - inner_id);
+                    push_extent(&mut v, first, len);
+                    inner_id += len;
+                }
+                push_extent(&mut v, 0, pos + len - hole_end);
             }
-        });
-        // alloc indirect2
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                self.indirect2 = new_blocks.next().unwrap();
+            pos += len;
+        }
+        self.store_extents(&v, alloc, block_device)
+    }
+    /// Get id of block given inner id, filling the hole there
+    /// and in the index blocks leading to it with blocks from `alloc`
+    fn alloc_block_id(
+        &mut self,
+        inner_id: u32,
+        alloc: &mut impl FnMut() -> u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> u32 {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            if self.direct[inner_id] == 0 {
+                self.direct[inner_id] = alloc();
             }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
-        } else {
-            return;
+            return self.direct[inner_id];
         }
-        // fill indirect2 from (a0, b0) -> (a1, b1)
-        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
-        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
-        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
-        // alloc low-level indirect1
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                if b0 == 0 {
-                    indirect2[a0] = new_blocks.next().unwrap();
-                }
-                // fill current
-                get_block_cache(
-                    indirect2[a0] as usize,
-                    Arc::clone(block_device)
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    indirect1[b0] = new_blocks.next().unwrap();
-                });
-                // move to next
-                b0 += 1;
-                if b0 == INODE_INDIRECT1_COUNT {
-                    b0 = 0;
-                    a0 += 1;
-                }
+        let (level, mut inner_id) = Self::locate(inner_id);
+        let indirect = self.indirect(level);
+        if *indirect == 0 {
+            *indirect = alloc();
+        }
+        let mut block_id = *indirect;
+        for level in (0..level).rev() {
+            let span = INODE_INDIRECT1_COUNT.pow(level as u32);
+            block_id = alloc_index(block_id, inner_id / span, alloc, block_device);
+            inner_id %= span;
+        }
+        block_id
+    }
+    /// Get ids of all the blocks owned by current disk inode,
+    /// including the index blocks
+    pub fn block_ids(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        if self.extents {
+            let mut v: Vec<u32> = self
+                .extents(block_device)
+                .into_iter()
+                .filter(|&(first, _)| first != 0)
+                .flat_map(|(first, len)| first..first + len)
+                .collect();
+            v.extend(self.extent_blocks(block_device));
+            return v;
+        }
+        let mut v: Vec<u32> = self.direct.iter().copied().filter(|&id| id != 0).collect();
+        for (level, &indirect) in [self.indirect1, self.indirect2, self.indirect3].iter().enumerate() {
+            if indirect != 0 {
+                collect_blocks(indirect, level + 1, &mut v, block_device);
             }
-        });
+        }
+        v
     }
-    /// Clear size to zero and return blocks that should be deallocated
-    /// and clear the block contents to zero later
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+    /// Decrease the size of current disk inode, zeroing what is left of the data
+    /// past the new size and returning the blocks that should be deallocated
+    pub fn decrease_size(
+        &mut self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        assert!(new_size <= self.size);
+        self.clear_tail(new_size, block_device);
+        // blocks past the size are always holes, so all blocks from the first
+        // one not needed anymore on are dropped
+        let keep = Self::_data_blocks(new_size) as usize;
+        self.size = new_size;
         let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
-        let mut current_blocks = 0usize;
-        // direct
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
-            current_blocks += 1;
-        }
-        // indirect1 block
-        if data_blocks > INODE_DIRECT_COUNT {
-            v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
-        } else {
-            return v;
+        let unused = self.drop_blocks(
+            keep,
+            &mut |block_id, level| collect_blocks(block_id, level, &mut v, block_device),
+            block_device,
+        );
+        v.extend(unused);
+        v
+    }
+    /// Clear the pointers to blocks past the size, which have to be holes,
+    /// without reading the blocks they lead to, and what follows the size in its block.
+    /// Returns the extent blocks which are not needed anymore.
+    pub fn clear_blocks_past_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        self.clear_tail(self.size, block_device);
+        let keep = Self::_data_blocks(self.size) as usize;
+        self.drop_blocks(keep, &mut |_, _| {}, block_device)
+    }
+    /// Zero the data past `size` in the block it ends in,
+    /// since growing again must read zeros back, not the old data
+    fn clear_tail(&self, size: u32, block_device: &Arc<dyn BlockDevice>) {
+        if size % BLOCK_SZ as u32 == 0 {
+            return;
         }
-        // indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                v.push(indirect1[current_blocks]);
-                //indirect1[current_blocks] = 0;
-                current_blocks += 1;
+        let block_id = self.get_block_id(size / BLOCK_SZ as u32, block_device);
+        if block_id != 0 {
+            let clear_tail = |data_block: &mut DataBlock| {
+                data_block[size as usize % BLOCK_SZ..].iter_mut().for_each(|p| *p = 0);
+            };
+            let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device));
+            if self.is_dir() {
+                block_cache.lock().modify(0, clear_tail);
+            } else {
+                block_cache.lock().modify_data(0, clear_tail);
             }
-        });
-        self.indirect1 = 0;
-        // indirect2 block
-        if data_blocks > INODE_INDIRECT1_COUNT {
-            v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
-        } else {
-            return v;
         }
-        // indirect2
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            // full indirect1 blocks
-            for i in 0..a1 {
-                v.push(indirect2[i]);
-                get_block_cache(
-                    indirect2[i] as usize,
-                    Arc::clone(block_device),
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..INODE_INDIRECT1_COUNT {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
-                    }
-                });
-                //indirect2[i] = 0;
+    }
+    /// Clear the pointers to all blocks from inner id `keep` on, passing each block
+    /// dropped whole to `discard` along with its level, 0 for a data block.
+    /// Returns the extent blocks which are not needed anymore.
+    fn drop_blocks(
+        &mut self,
+        keep: usize,
+        discard: &mut impl FnMut(u32, usize),
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        if self.extents {
+            let mut kept: Vec<(u32, u32)> = Vec::new();
+            let mut pos = 0;
+            for (first, len) in self.extents(block_device) {
+                let kept_len = (keep as u32).saturating_sub(pos).min(len);
+                push_extent(&mut kept, first, kept_len);
+                if first != 0 {
+                    (first + kept_len..first + len).for_each(|block_id| discard(block_id, 0));
+                }
+                pos += len;
             }
-            // last indirect1 block
-            if b1 > 0 {
-                v.push(indirect2[a1]);
-                get_block_cache(
-                    indirect2[a1] as usize,
-                    Arc::clone(block_device),
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..b1 {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
-                    }
-                });
-                //indirect2[a1] = 0;
+            // a hole at the end is implied
+            if kept.last().map_or(false, |&(first, _)| first == 0) {
+                kept.pop();
             }
-        });
-        self.indirect2 = 0;
+            return self.store_extents(
+                &kept,
+                &mut |_, _| panic!("Shrinking needs no more extent blocks!"),
+                block_device,
+            );
+        }
+        // direct
+        for block_id in self.direct.iter_mut().skip(keep) {
+            if *block_id != 0 {
+                discard(*block_id, 0);
+                *block_id = 0;
+            }
+        }
+        // indirect
+        let bounds = [DIRECT_BOUND, INDIRECT1_BOUND, INDIRECT2_BOUND, INDIRECT3_BOUND];
+        for level in 1..=3 {
+            let from = keep.saturating_sub(bounds[level - 1]);
+            let indirect = self.indirect(level);
+            if *indirect != 0
+                && from < bounds[level] - bounds[level - 1]
+                && truncate_blocks(*indirect, level, from, discard, block_device)
+            {
+                *indirect = 0;
+            }
+        }
+        Vec::new()
+    }
+    /// Get the extents of current disk inode in file order.
+    /// An extent starting at block 0 is a hole, and so is everything past the last one.
+    pub fn extents(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<(u32, u32)> {
+        let mut v: Vec<(u32, u32)> = self
+            .direct
+            .chunks(2)
+            .take_while(|pair| pair[1] != 0)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+        let mut block_id = self.indirect1;
+        while block_id != 0 {
+            let (next, extents) = read_extent_block(block_id, block_device);
+            v.extend(extents);
+            block_id = next;
+        }
+        v
+    }
+    /// Get the number of extent blocks which filling holes from inner id `start` on
+    /// rewrites, as the extents after `start` shift; 0 if current disk inode has no extents
+    pub fn shifted_extent_blocks(&self, start: u32, block_device: &Arc<dyn BlockDevice>) -> usize {
+        if !self.extents {
+            return 0;
+        }
+        let extents = self.extents(block_device);
+        let mut pos = 0;
+        let first_shifted = extents
+            .iter()
+            .position(|&(_, len)| {
+                pos += len;
+                pos > start
+            })
+            .unwrap_or(extents.len());
+        let blocks = |count: usize| {
+            (count.saturating_sub(INODE_EXTENT_COUNT) + EXTENT_BLOCK_COUNT - 1) / EXTENT_BLOCK_COUNT
+        };
+        blocks(extents.len()) - first_shifted.saturating_sub(INODE_EXTENT_COUNT) / EXTENT_BLOCK_COUNT
+    }
+    /// Get the chain of extent blocks of current disk inode
+    fn extent_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::new();
+        let mut block_id = self.indirect1;
+        while block_id != 0 {
+            v.push(block_id);
+            block_id = read_extent_block(block_id, block_device).0;
+        }
         v
     }
+    /// Store the extents of current disk inode, reusing its extent blocks
+    /// and taking more from `alloc` as needed.
+    /// Returns the extent blocks which are not needed anymore.
+    fn store_extents(
+        &mut self,
+        extents: &[(u32, u32)],
+        alloc: &mut impl FnMut(u32, u32) -> (u32, u32),
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        let inline = extents.len().min(INODE_EXTENT_COUNT);
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        for (pair, &(first, len)) in self.direct.chunks_mut(2).zip(extents[..inline].iter()) {
+            pair[0] = first;
+            pair[1] = len;
+        }
+        let chunks: Vec<&[(u32, u32)]> = extents[inline..].chunks(EXTENT_BLOCK_COUNT).collect();
+        let mut blocks = self.extent_blocks(block_device);
+        let unused = blocks.split_off(chunks.len().min(blocks.len()));
+        while blocks.len() < chunks.len() {
+            blocks.push(alloc(0, 1).0);
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut new_block: IndirectBlock = [0; INODE_INDIRECT1_COUNT];
+            new_block[0] = blocks.get(i + 1).copied().unwrap_or(0);
+            for (pair, &(first, len)) in new_block[2..].chunks_mut(2).zip(chunk.iter()) {
+                pair[0] = first;
+                pair[1] = len;
+            }
+            // the blocks left as they are stay out of the journal
+            let block_cache = get_block_cache(blocks[i] as usize, Arc::clone(block_device));
+            let mut block_cache = block_cache.lock();
+            if block_cache.read(0, |extent_block: &IndirectBlock| *extent_block != new_block) {
+                block_cache.modify(0, |extent_block: &mut IndirectBlock| *extent_block = new_block);
+            }
+        }
+        self.indirect1 = blocks.first().copied().unwrap_or(0);
+        unused
+    }
     /// Read data from current disk inode
     pub fn read_at(
         &self,
@@ -364,15 +574,21 @@ impl DiskInode {
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            let block_id = self.get_block_id(start_block as u32, block_device);
+            if block_id == 0 {
+                // holes read as zeros
+                dst.iter_mut().for_each(|p| *p = 0);
+            } else {
+                get_block_cache(
+                    block_id as usize,
+                    Arc::clone(block_device),
+                )
+                .lock()
+                .read(0, |data_block: &DataBlock| {
+                    let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                    dst.copy_from_slice(src);
+                });
+            }
             read_size += block_read_size;
             // move to next block
             if end_current_block == end { break; }
@@ -382,7 +598,7 @@ impl DiskInode {
         read_size
     }
     /// Write data into current disk inode
-    /// size must be adjusted properly beforehand
+    /// size must be adjusted and holes filled properly beforehand
     pub fn write_at(
         &mut self,
         offset: usize,
@@ -394,22 +610,27 @@ impl DiskInode {
         assert!(start <= end);
         let mut start_block = start / BLOCK_SZ;
         let mut write_size = 0usize;
+        // directory contents are metadata, file contents are not
+        let is_dir = self.is_dir();
         loop {
             // calculate end of current block
             let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
+            let write_block = |data_block: &mut DataBlock| {
                 let src = &buf[write_size..write_size + block_write_size];
                 let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
                 dst.copy_from_slice(src);
-            });
+            };
+            let block_id = self.get_block_id(start_block as u32, block_device);
+            assert_ne!(block_id, 0, "Writing into a hole!");
+            let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device));
+            if is_dir {
+                block_cache.lock().modify(0, write_block);
+            } else {
+                block_cache.lock().modify_data(0, write_block);
+            }
             write_size += block_write_size;
             // move to next block
             if end_current_block == end { break; }
@@ -420,58 +641,283 @@ impl DiskInode {
     }
 }
 
-/// A directory entry
-#[repr(C)]
-pub struct DirEntry {
-    name: [u8; NAME_LENGTH_LIMIT + 1],
-    inode_number: u32,
+/// Read an extent block, returns (next extent block, extents in it)
+pub fn read_extent_block(
+    block_id: u32,
+    block_device: &Arc<dyn BlockDevice>,
+) -> (u32, Vec<(u32, u32)>) {
+    get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |extent_block: &IndirectBlock| {
+            let extents = extent_block[2..]
+                .chunks(2)
+                .take_while(|pair| pair[1] != 0)
+                .map(|pair| (pair[0], pair[1]))
+                .collect();
+            (extent_block[0], extents)
+        })
 }
 
-/// Size of a directory entry
-pub const DIRENT_SZ: usize = 32;
+/// Append an extent, merging it into the last one when they are adjacent
+fn push_extent(v: &mut Vec<(u32, u32)>, first: u32, len: u32) {
+    if len == 0 {
+        return;
+    }
+    if let Some(last) = v.last_mut() {
+        let adjacent = if first == 0 { last.0 == 0 } else { last.0 != 0 && last.0 + last.1 == first };
+        if adjacent {
+            last.1 += len;
+            return;
+        }
+    }
+    v.push((first, len));
+}
 
-impl DirEntry {
-    /// Create an empty directory entry
-    pub fn empty() -> Self {
-        Self {
-            name: [0u8; NAME_LENGTH_LIMIT + 1],
-            inode_number: 0,
+/// Get an entry of an index block, or 0 if the index block is a hole itself
+fn read_index(block_id: u32, index: usize, block_device: &Arc<dyn BlockDevice>) -> u32 {
+    if block_id == 0 {
+        return 0;
+    }
+    get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |index_block: &IndirectBlock| index_block[index])
+}
+
+/// Get an entry of an index block, filling it with a block from `alloc` if it is a hole
+fn alloc_index(
+    block_id: u32,
+    index: usize,
+    alloc: &mut impl FnMut() -> u32,
+    block_device: &Arc<dyn BlockDevice>,
+) -> u32 {
+    let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device));
+    let mut block_cache = block_cache.lock();
+    let entry = block_cache.read(0, |index_block: &IndirectBlock| index_block[index]);
+    if entry != 0 {
+        return entry;
+    }
+    let entry = alloc();
+    block_cache.modify(0, |index_block: &mut IndirectBlock| index_block[index] = entry);
+    entry
+}
+
+/// Get the entries of an index block which are not holes
+fn index_entries(block_id: u32, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+    get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |index_block: &IndirectBlock| {
+            index_block.iter().copied().filter(|&id| id != 0).collect()
+        })
+}
+
+/// Collect a block of the given level, 0 for a data block, with all the blocks under it
+fn collect_blocks(
+    block_id: u32,
+    level: usize,
+    v: &mut Vec<u32>,
+    block_device: &Arc<dyn BlockDevice>,
+) {
+    if level > 0 {
+        for entry in index_entries(block_id, block_device) {
+            collect_blocks(entry, level - 1, v, block_device);
         }
     }
-    /// Crate a directory entry from name and inode number
-    pub fn new(name: &str, inode_number: u32) -> Self {
-        let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
-        bytes[..name.len()].copy_from_slice(name.as_bytes());
-        Self {
-            name: bytes,
-            inode_number,
+    v.push(block_id);
+}
+
+/// Clear the entries under a block of the given level from the given inner id on,
+/// passing each block dropped whole to `discard` along with its level.
+/// Returns whether the block itself has been dropped whole.
+fn truncate_blocks(
+    block_id: u32,
+    level: usize,
+    from: usize,
+    discard: &mut impl FnMut(u32, usize),
+    block_device: &Arc<dyn BlockDevice>,
+) -> bool {
+    if from == 0 {
+        discard(block_id, level);
+        return true;
+    }
+    let span = INODE_INDIRECT1_COUNT.pow(level as u32 - 1);
+    let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device));
+    let mut block_cache = block_cache.lock();
+    let entries = block_cache.read(0, |index_block: &IndirectBlock| *index_block);
+    let mut dropped: Vec<usize> = Vec::new();
+    for (i, &entry) in entries.iter().enumerate().skip(from / span) {
+        let inner_from = from.saturating_sub(i * span);
+        if entry != 0 && truncate_blocks(entry, level - 1, inner_from, discard, block_device) {
+            dropped.push(i);
         }
     }
+    if !dropped.is_empty() {
+        block_cache.modify(0, |index_block: &mut IndirectBlock| {
+            dropped.iter().for_each(|&i| index_block[i] = 0);
+        });
+    }
+    false
+}
+
+/// Size of the header of a directory record: inode number, record length and name length
+const DIRENT_HEADER_SZ: usize = 8;
+
+/// Length of a record just large enough for a name of the given length,
+/// records being aligned to 4 bytes
+fn record_len(name_len: usize) -> usize {
+    (DIRENT_HEADER_SZ + name_len + 3) & !3
+}
+
+/// A directory block, tiled by records which never cross into the next block.
+/// A record holds a directory entry and may leave room after it up to the next record.
+/// It stores the inode number plus one, as the root is inode 0,
+/// so that a record storing 0 holds nothing.
+#[repr(C)]
+pub struct DirBlock([u8; BLOCK_SZ]);
+
+/// A directory entry in a directory block
+pub struct DirEntry<'a> {
+    /// Offset of the record in the directory block
+    pub offset: usize,
+    pub inode_number: u32,
+    pub name: &'a str,
+}
+
+impl DirBlock {
+    /// Create a directory block with a single record holding nothing
+    pub fn empty() -> Self {
+        let mut dir_block = Self([0; BLOCK_SZ]);
+        dir_block.set_record(0, None, BLOCK_SZ, "");
+        dir_block
+    }
     /// Serialize into bytes
     pub fn as_bytes(&self) -> &[u8] {
-        unsafe {
-            core::slice::from_raw_parts(
-                self as *const _ as usize as *const u8,
-                DIRENT_SZ,
-            )
-        }
+        &self.0
     }
     /// Serialize into mutable bytes
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            core::slice::from_raw_parts_mut(
-                self as *mut _ as usize as *mut u8,
-                DIRENT_SZ,
-            )
+        &mut self.0
+    }
+    /// Get (stored inode number, record length, name length) of the record at an offset,
+    /// or None if it is malformed
+    fn record(&self, offset: usize) -> Option<(u32, usize, usize)> {
+        if offset + DIRENT_HEADER_SZ > BLOCK_SZ {
+            return None;
+        }
+        let header = &self.0[offset..offset + DIRENT_HEADER_SZ];
+        let inode_number = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let rec_len = u16::from_le_bytes([header[4], header[5]]) as usize;
+        let name_len = header[6] as usize;
+        if rec_len % 4 != 0 || rec_len < record_len(name_len) || offset + rec_len > BLOCK_SZ {
+            return None;
+        }
+        Some((inode_number, rec_len, name_len))
+    }
+    /// Get the records up to the first malformed one,
+    /// as (offset, stored inode number, record length, name length)
+    fn records(&self) -> Vec<(usize, u32, usize, usize)> {
+        let mut v = Vec::new();
+        let mut offset = 0;
+        while offset < BLOCK_SZ {
+            match self.record(offset) {
+                Some((inode_number, rec_len, name_len)) => {
+                    v.push((offset, inode_number, rec_len, name_len));
+                    offset += rec_len;
+                }
+                None => break,
+            }
         }
+        v
+    }
+    /// Write a record, holding nothing if `inode_number` is None
+    fn set_record(&mut self, offset: usize, inode_number: Option<u32>, rec_len: usize, name: &str) {
+        let stored = inode_number.map_or(0, |inode_number| inode_number + 1);
+        let record = &mut self.0[offset..offset + record_len(name.len())];
+        record[..4].copy_from_slice(&stored.to_le_bytes());
+        record[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+        record[6] = name.len() as u8;
+        record[7] = 0;
+        record[DIRENT_HEADER_SZ..DIRENT_HEADER_SZ + name.len()].copy_from_slice(name.as_bytes());
+    }
+    /// Change the length of a record
+    fn set_rec_len(&mut self, offset: usize, rec_len: usize) {
+        self.0[offset + 4..offset + 6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    }
+    /// Get the name of a record, or None if it is not valid UTF-8
+    fn name(&self, offset: usize, name_len: usize) -> Option<&str> {
+        let start = offset + DIRENT_HEADER_SZ;
+        core::str::from_utf8(&self.0[start..start + name_len]).ok()
+    }
+    /// Whether the records tile the whole block and all names are valid
+    pub fn is_valid(&self) -> bool {
+        let records = self.records();
+        let tiled = records
+            .last()
+            .map_or(false, |&(offset, _, rec_len, _)| offset + rec_len == BLOCK_SZ);
+        tiled && records.iter().all(|&(offset, _, _, name_len)| self.name(offset, name_len).is_some())
+    }
+    /// Get the directory entries, up to the first malformed record
+    pub fn entries(&self) -> Vec<DirEntry<'_>> {
+        self.records()
+            .into_iter()
+            .filter(|&(_, stored, _, _)| stored != 0)
+            .filter_map(|(offset, stored, _, name_len)| {
+                Some(DirEntry {
+                    offset,
+                    inode_number: stored - 1,
+                    name: self.name(offset, name_len)?,
+                })
+            })
+            .collect()
     }
-    /// Get name of the entry
-    pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+    /// Get the directory entry at an offset
+    pub fn entry_at(&self, offset: usize) -> Option<DirEntry<'_>> {
+        self.entries().into_iter().find(|dirent| dirent.offset == offset)
     }
-    /// Get inode number of the entry
-    pub fn inode_number(&self) -> u32 {
-        self.inode_number
+    /// Add a directory entry where a record has room for it, splitting that record.
+    /// Returns the offset of the new entry, or None if there is no room.
+    pub fn insert(&mut self, name: &str, inode_number: u32) -> Option<usize> {
+        let needed = record_len(name.len());
+        for (offset, stored, rec_len, name_len) in self.records() {
+            if stored == 0 && rec_len >= needed {
+                self.set_record(offset, Some(inode_number), rec_len, name);
+                return Some(offset);
+            }
+            let used = record_len(name_len);
+            if stored != 0 && rec_len - used >= needed {
+                self.set_rec_len(offset, used);
+                self.set_record(offset + used, Some(inode_number), rec_len - used, name);
+                return Some(offset + used);
+            }
+        }
+        None
+    }
+    /// Point the directory entry at an offset to another inode
+    pub fn set_inode(&mut self, offset: usize, inode_number: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&(inode_number + 1).to_le_bytes());
+    }
+    /// Remove the directory entry at an offset, handing its room to the record before it
+    pub fn remove(&mut self, offset: usize) {
+        let records = self.records();
+        let i = records.iter().position(|&(o, _, _, _)| o == offset).unwrap();
+        let rec_len = records[i].2;
+        if i == 0 {
+            self.set_record(offset, None, rec_len, "");
+        } else {
+            let (prev_offset, _, prev_rec_len, _) = records[i - 1];
+            self.set_rec_len(prev_offset, prev_rec_len + rec_len);
+        }
+    }
+    /// Drop the records from the first malformed one on, and entries with invalid names
+    pub fn repair(&mut self) {
+        let records = self.records();
+        match records.last() {
+            Some(&(offset, _, _, _)) => self.set_rec_len(offset, BLOCK_SZ - offset),
+            None => *self = Self::empty(),
+        }
+        for &(offset, stored, _, name_len) in records.iter().rev() {
+            if stored != 0 && self.name(offset, name_len).is_none() {
+                self.remove(offset);
+            }
+        }
     }
 }
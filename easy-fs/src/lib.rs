@@ -8,12 +8,33 @@ mod efs;
 mod bitmap;
 mod vfs;
 mod block_cache;
+mod journal;
+mod fsck;
+mod clock;
+mod dir_index;
 
 /// Use a block size of 512 bytes
 pub const BLOCK_SZ: usize = 512;
 pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
-pub use vfs::Inode;
+pub use efs::{EasyFileSystem, FsUsage};
+pub use layout::{SuperBlock, NAME_LENGTH_LIMIT, MAX_FILE_SIZE};
+pub use vfs::{Dirent, Inode, Metadata};
+pub use clock::set_clock;
+pub use fsck::FsckProblem;
+pub use block_cache::{BlockCacheStats, BLOCK_CACHE_SIZE, block_cache_stats};
 use layout::*;
 use bitmap::Bitmap;
-use block_cache::{get_block_cache, block_cache_sync_all};
+use journal::{Journal, TRANSACTION_MAX_BLOCKS};
+use dir_index::DirIndex;
+use clock::now;
+use block_cache::{
+    get_block_cache,
+    begin_transaction,
+    end_transaction,
+    logged_blocks,
+    block_cache_sync,
+    block_cache_sync_all,
+    block_cache_sync_device,
+    block_device_cache_stats,
+    reserve_block_cache_capacity,
+};
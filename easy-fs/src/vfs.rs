@@ -1,20 +1,60 @@
 use super::{
+    BLOCK_SZ,
     BlockDevice,
     DiskInode,
     DiskInodeType,
-    DirEntry,
+    DirBlock,
     EasyFileSystem,
-    DIRENT_SZ,
+    MAX_FILE_SIZE,
+    NAME_LENGTH_LIMIT,
     get_block_cache,
-    block_cache_sync_all,
+    block_cache_sync,
+    now,
 };
 use alloc::sync::Arc;
 use alloc::string::String;
 use alloc::vec::Vec;
 use spin::{Mutex, MutexGuard};
 
+/// Holes in files are filled for at most this many bytes per transaction,
+/// which keeps the bitmap and index blocks each modifies within `TRANSACTION_MAX_BLOCKS`
+const GROW_STEP: usize = 16 * BLOCK_SZ;
+
+/// Holes are only filled where that rewrites at most this many extent blocks,
+/// which leaves room for them in a transaction next to the blocks of a step
+const SHIFTED_EXTENT_BLOCKS_MAX: usize = 12;
+
+/// Metadata of an inode besides its size and type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    /// Permission bits, rwx for the owner, the group and others
+    pub perm: u16,
+    /// User id of the owner
+    pub uid: u32,
+    /// Group id of the owner
+    pub gid: u32,
+    /// Time of last access, in seconds since the epoch
+    pub atime: u64,
+    /// Time of last modification of the data
+    pub mtime: u64,
+    /// Time of last change of the data or the metadata
+    pub ctime: u64,
+}
+
+/// An entry of a directory, as read by `Inode::read_dir`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dirent {
+    /// Inode number of the entry
+    pub inode_id: u32,
+    /// Name of the entry
+    pub name: String,
+    /// Whether the entry is a directory
+    pub is_dir: bool,
+}
+
 /// Virtual filesystem layer over easy-fs
 pub struct Inode {
+    inode_id: u32,
     block_id: usize,
     block_offset: usize,
     fs: Arc<Mutex<EasyFileSystem>>,
@@ -24,12 +64,14 @@ pub struct Inode {
 impl Inode {
     /// Create a vfs inode
     pub fn new(
+        inode_id: u32,
         block_id: u32,
         block_offset: usize,
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
             fs,
@@ -50,48 +92,172 @@ impl Inode {
             Arc::clone(&self.block_device)
         ).lock().modify(self.block_offset, f)
     }
-    /// Find inode under a disk inode by name
-    fn find_inode_id(
+    /// Get the inode number of current inode
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+    /// Whether current inode is a directory
+    pub fn is_dir(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+    /// Get the number of hard links to current inode
+    pub fn nlink(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.nlink)
+    }
+    /// Get the size of current inode in bytes
+    pub fn size(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.size)
+    }
+    /// Get the permissions, owner and timestamps of current inode
+    pub fn metadata(&self) -> Metadata {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| Metadata {
+            perm: disk_inode.perm,
+            uid: disk_inode.uid,
+            gid: disk_inode.gid,
+            atime: disk_inode.atime,
+            mtime: disk_inode.mtime,
+            ctime: disk_inode.ctime,
+        })
+    }
+    /// Set the permissions, owner, access time and modification time of current inode,
+    /// the change time becomes the current time instead
+    pub fn set_metadata(&self, metadata: Metadata) {
+        let fs = self.fs.lock();
+        fs.begin();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.perm = metadata.perm & 0o7777;
+            disk_inode.uid = metadata.uid;
+            disk_inode.gid = metadata.gid;
+            disk_inode.atime = metadata.atime;
+            disk_inode.mtime = metadata.mtime;
+            disk_inode.ctime = now();
+        });
+        fs.commit();
+    }
+    /// Build a vfs inode for the given inode number
+    fn get_inode(&self, inode_id: u32, fs: &EasyFileSystem) -> Arc<Inode> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Arc::new(Self::new(
+            inode_id,
+            block_id,
+            block_offset,
+            self.fs.clone(),
+            self.block_device.clone(),
+        ))
+    }
+    /// Read a block of a directory
+    fn read_dir_block(&self, block: usize, disk_inode: &DiskInode) -> DirBlock {
+        let mut dir_block = DirBlock::empty();
+        disk_inode.read_at(block * BLOCK_SZ, dir_block.as_bytes_mut(), &self.block_device);
+        dir_block
+    }
+    /// Write a block of a directory
+    fn write_dir_block(&self, block: usize, dir_block: &DirBlock, disk_inode: &mut DiskInode) {
+        disk_inode.write_at(block * BLOCK_SZ, dir_block.as_bytes(), &self.block_device);
+    }
+    /// Find a dirent under a disk inode by name, indexing the directory first if needed
+    /// returns (position of the dirent, inode number)
+    fn find_dirent(
         &self,
         name: &str,
         disk_inode: &DiskInode,
-    ) -> Option<u32> {
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Option<(u32, u32)> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(
-                    DIRENT_SZ * i,
-                    dirent.as_bytes_mut(),
-                    &self.block_device,
-                ),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
+        let positions = match fs.dir_index.lookup(self.inode_id, name) {
+            Some(positions) => positions,
+            None => {
+                fs.dir_index.index(self.inode_id);
+                for block in 0..disk_inode.size as usize / BLOCK_SZ {
+                    for dirent in self.read_dir_block(block, disk_inode).entries() {
+                        let position = (block * BLOCK_SZ + dirent.offset) as u32;
+                        fs.dir_index.insert(self.inode_id, dirent.name, position);
+                    }
+                }
+                fs.dir_index.lookup(self.inode_id, name).unwrap()
             }
-        }
-        None
+        };
+        // positions of other names with the same hash are weeded out here
+        positions.into_iter().find_map(|position| {
+            let block = position as usize / BLOCK_SZ;
+            self.read_dir_block(block, disk_inode)
+                .entry_at(position as usize % BLOCK_SZ)
+                .filter(|dirent| dirent.name == name)
+                .map(|dirent| (position, dirent.inode_number))
+        })
+    }
+    /// Find inode under a disk inode by name
+    fn find_inode_id(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Option<u32> {
+        self.find_dirent(name, disk_inode, fs)
+            .map(|(_, inode_id)| inode_id)
+    }
+    /// Whether a directory holds nothing but "." and ".."
+    fn is_empty_dir(&self, disk_inode: &DiskInode) -> bool {
+        (0..disk_inode.size as usize / BLOCK_SZ).all(|block| {
+            self.read_dir_block(block, disk_inode)
+                .entries()
+                .iter()
+                .all(|dirent| dirent.name == "." || dirent.name == "..")
+        })
     }
     /// Find inode under current inode by name
     pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let fs = self.fs.lock();
+        let mut fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode)
-            .map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
+            if !disk_inode.is_dir() {
+                return None;
+            }
+            self.find_inode_id(name, disk_inode, &mut fs)
+            .map(|inode_id| self.get_inode(inode_id, &fs))
         })
     }
-    /// Increase the size of a disk inode
+    /// Find inode by path, which is resolved from the root inode
+    /// if it is absolute and from current inode otherwise
+    pub fn find_path(&self, path: &str) -> Option<Arc<Inode>> {
+        let mut inode = {
+            let fs = self.fs.lock();
+            let start_inode_id = if path.starts_with('/') { 0 } else { self.inode_id };
+            self.get_inode(start_inode_id, &fs)
+        };
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode = inode.find(name)?;
+        }
+        Some(inode)
+    }
+    /// Fill the holes among the blocks holding bytes [start, end) of a disk inode
+    fn alloc_blocks(
+        &self,
+        start: usize,
+        end: usize,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) {
+        let unused = disk_inode.alloc_blocks(
+            (start / BLOCK_SZ) as u32,
+            ((end + BLOCK_SZ - 1) / BLOCK_SZ) as u32,
+            &mut |goal, max| fs.alloc_data_run(goal, max),
+            &self.block_device,
+        );
+        for block_id in unused {
+            fs.dealloc_data(block_id);
+        }
+    }
+    /// Whether some of the blocks holding bytes [start, end) of a disk inode are holes
+    fn has_holes(&self, start: usize, end: usize, disk_inode: &DiskInode) -> bool {
+        (start / BLOCK_SZ..(end + BLOCK_SZ - 1) / BLOCK_SZ)
+            .any(|inner_id| disk_inode.get_block_id(inner_id as u32, &self.block_device) == 0)
+    }
+    /// Increase the size of a disk inode, with blocks for all of it
     fn increase_size(
         &self,
         new_size: u32,
@@ -101,27 +267,36 @@ impl Inode {
         if new_size < disk_inode.size {
             return;
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
-        }
-        disk_inode.increase_size(new_size, v, &self.block_device);
+        self.alloc_blocks(disk_inode.size as usize, new_size as usize, disk_inode, fs);
+        disk_inode.size = new_size;
     }
-    /// Create inode under current inode by name
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
+    /// Allocate a new inode of the given type and append a dirent
+    /// pointing to it under current inode
+    fn create_inode(
+        &self,
+        name: &str,
+        type_: DiskInodeType,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Option<u32> {
+        if name.is_empty() || name.len() > NAME_LENGTH_LIMIT {
+            return None;
+        }
+        // nothing can be added to a removed directory
+        if fs.orphans.contains(&self.inode_id) {
+            return None;
+        }
         if self.modify_disk_inode(|root_inode| {
             // assert it is a directory
             assert!(root_inode.is_dir());
             // has the file been created?
-            self.find_inode_id(name, root_inode)
+            self.find_inode_id(name, root_inode, fs)
         }).is_some() {
             return None;
         }
         // create a new file
         // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
+        let new_inode_id = fs.alloc_inode()?;
+        let extents = fs.extents && type_ == DiskInodeType::File;
         // initialize inode
         let (new_inode_block_id, new_inode_block_offset) 
             = fs.get_disk_inode_pos(new_inode_id);
@@ -129,83 +304,489 @@ impl Inode {
             new_inode_block_id as usize,
             Arc::clone(&self.block_device)
         ).lock().modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-            new_inode.initialize(DiskInodeType::File);
+            new_inode.initialize(type_, now());
+            if extents {
+                new_inode.use_extents();
+            }
         });
+        self.append_dirent(name, new_inode_id, fs);
+        Some(new_inode_id)
+    }
+    /// Add a dirent to current inode,
+    /// in the first directory block with room for it
+    fn append_dirent(
+        &self,
+        name: &str,
+        inode_id: u32,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) {
         self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
+            let blocks = root_inode.size as usize / BLOCK_SZ;
+            let found = (0..blocks).find_map(|block| {
+                let mut dir_block = self.read_dir_block(block, root_inode);
+                dir_block
+                    .insert(name, inode_id)
+                    .map(|offset| (block, dir_block, offset))
+            });
+            let (block, dir_block, offset) = match found {
+                Some(found) => found,
+                None => {
+                    // append a directory block
+                    self.increase_size(((blocks + 1) * BLOCK_SZ) as u32, root_inode, fs);
+                    let mut dir_block = DirBlock::empty();
+                    let offset = dir_block.insert(name, inode_id).unwrap();
+                    (blocks, dir_block, offset)
+                }
+            };
+            self.write_dir_block(block, &dir_block, root_inode);
+            fs.dir_index.insert(self.inode_id, name, (block * BLOCK_SZ + offset) as u32);
+            root_inode.set_modified(now());
         });
-
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        block_cache_sync_all();
+    }
+    /// Remove the dirent at the given position from current inode
+    fn remove_dirent(&self, position: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        self.modify_disk_inode(|disk_inode| {
+            let (block, offset) = (position as usize / BLOCK_SZ, position as usize % BLOCK_SZ);
+            let mut dir_block = self.read_dir_block(block, disk_inode);
+            let name = String::from(dir_block.entry_at(offset).unwrap().name);
+            dir_block.remove(offset);
+            self.write_dir_block(block, &dir_block, disk_inode);
+            fs.dir_index.remove(self.inode_id, &name, position);
+            disk_inode.set_modified(now());
+        });
+    }
+    /// Point the dirent at the given position of current inode to another inode
+    fn set_dirent_inode(&self, position: u32, inode_id: u32) {
+        self.modify_disk_inode(|disk_inode| {
+            let (block, offset) = (position as usize / BLOCK_SZ, position as usize % BLOCK_SZ);
+            let mut dir_block = self.read_dir_block(block, disk_inode);
+            dir_block.set_inode(offset, inode_id);
+            self.write_dir_block(block, &dir_block, disk_inode);
+            disk_inode.set_modified(now());
+        });
+    }
+    /// Whether current inode is the directory of the given inode number or lies under it
+    fn is_under(&self, dir_id: u32, fs: &mut MutexGuard<EasyFileSystem>) -> bool {
+        let mut inode_id = self.inode_id;
+        loop {
+            if inode_id == dir_id {
+                return true;
+            }
+            if inode_id == 0 {
+                return false;
+            }
+            let inode = self.get_inode(inode_id, fs);
+            inode_id = match inode.read_disk_inode(|disk_inode| {
+                inode.find_inode_id("..", disk_inode, fs)
+            }) {
+                Some(parent_id) => parent_id,
+                None => return false,
+            };
+        }
+    }
+    /// Fill "." and ".." into current inode, which must be an empty directory
+    pub(crate) fn initialize_dir(
+        &self,
+        parent_inode_id: u32,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) {
+        self.modify_disk_inode(|disk_inode| {
+            assert!(disk_inode.is_dir() && disk_inode.size == 0);
+            self.increase_size(BLOCK_SZ as u32, disk_inode, fs);
+            let mut dir_block = DirBlock::empty();
+            dir_block.insert(".", self.inode_id);
+            dir_block.insert("..", parent_inode_id);
+            self.write_dir_block(0, &dir_block, disk_inode);
+        });
+    }
+    /// Create inode under current inode by name
+    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+        let mut fs = self.fs.lock();
+        fs.begin();
+        let new_inode_id = self.create_inode(name, DiskInodeType::File, &mut fs);
+        fs.commit();
+        let inode = self.get_inode(new_inode_id?, &fs);
         // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
+        Some(inode)
         // release efs lock automatically by compiler
     }
+    /// Create a directory under current inode by name
+    pub fn mkdir(&self, name: &str) -> Option<Arc<Inode>> {
+        let mut fs = self.fs.lock();
+        fs.begin();
+        let inode = self
+            .create_inode(name, DiskInodeType::Directory, &mut fs)
+            .map(|new_inode_id| {
+                let inode = self.get_inode(new_inode_id, &fs);
+                inode.initialize_dir(self.inode_id, &mut fs);
+                inode
+            });
+        fs.commit();
+        inode
+    }
+    /// Shrink current inode to `new_size`, outside of a transaction.
+    /// The inode lets go of the blocks past the new size in one transaction,
+    /// which are then freed in transactions of their own.
+    fn shrink(&self, new_size: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        fs.begin();
+        let data_blocks = self.modify_disk_inode(|disk_inode| {
+            let data_blocks = disk_inode.decrease_size(new_size, &self.block_device);
+            disk_inode.set_modified(now());
+            data_blocks
+        });
+        fs.commit();
+        fs.dealloc_data_blocks(data_blocks);
+    }
+    /// Deallocate current inode together with all of its data blocks,
+    /// outside of a transaction.
+    /// The inode is only freed after its blocks, so a crash partway
+    /// leaves it unreachable for `EasyFileSystem::check` to reclaim.
+    fn release(&self, fs: &mut MutexGuard<EasyFileSystem>) {
+        self.shrink(0, fs);
+        fs.begin();
+        fs.dir_index.forget(self.inode_id);
+        fs.dealloc_inode(self.inode_id);
+        fs.commit();
+    }
+    /// Deallocate current inode, whose last name is gone, outside of a transaction,
+    /// at once if no handle is open on it and once the last one is closed otherwise
+    fn release_unless_open(&self, fs: &mut MutexGuard<EasyFileSystem>) {
+        if fs.open_handles.contains_key(&self.inode_id) {
+            fs.orphans.insert(self.inode_id);
+        } else {
+            self.release(fs);
+        }
+    }
+    /// Open a handle on current inode, which keeps the inode and its data
+    /// from being released while the handle is open, even after its last name is gone
+    pub fn open_handle(&self) {
+        let mut fs = self.fs.lock();
+        *fs.open_handles.entry(self.inode_id).or_insert(0) += 1;
+    }
+    /// Close a handle opened by `open_handle`,
+    /// releasing current inode if it was the last handle and no name is left
+    pub fn close_handle(&self) {
+        let mut fs = self.fs.lock();
+        let handles = fs.open_handles.get_mut(&self.inode_id).expect("No handle to close!");
+        *handles -= 1;
+        if *handles > 0 {
+            return;
+        }
+        fs.open_handles.remove(&self.inode_id);
+        if fs.orphans.remove(&self.inode_id) {
+            self.release(&mut fs);
+        }
+    }
+    /// Remove an empty directory under current inode by name
+    pub fn rmdir(&self, name: &str) -> bool {
+        if name == "." || name == ".." {
+            return false;
+        }
+        let mut fs = self.fs.lock();
+        let (position, inode_id) = match self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return None;
+            }
+            self.find_dirent(name, disk_inode, &mut fs)
+        }) {
+            Some(pair) => pair,
+            None => return false,
+        };
+        let inode = self.get_inode(inode_id, &fs);
+        let removable = inode.read_disk_inode(|disk_inode| {
+            disk_inode.is_dir() && inode.is_empty_dir(disk_inode)
+        });
+        if !removable {
+            return false;
+        }
+        fs.begin();
+        self.remove_dirent(position, &mut fs);
+        fs.commit();
+        inode.release_unless_open(&mut fs);
+        true
+    }
+    /// Create a new name under current inode for an existing file
+    pub fn link(&self, name: &str, inode: &Inode) -> bool {
+        let mut fs = self.fs.lock();
+        if name.is_empty() || name.len() > NAME_LENGTH_LIMIT {
+            return false;
+        }
+        if fs.orphans.contains(&self.inode_id) {
+            return false;
+        }
+        if self.read_disk_inode(|disk_inode| {
+            !disk_inode.is_dir() || self.find_inode_id(name, disk_inode, &mut fs).is_some()
+        }) {
+            return false;
+        }
+        // hard links to directories are not allowed
+        if inode.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return false;
+        }
+        fs.begin();
+        self.append_dirent(name, inode.inode_id, &mut fs);
+        inode.modify_disk_inode(|disk_inode| {
+            disk_inode.nlink += 1;
+            disk_inode.ctime = now();
+        });
+        fs.commit();
+        true
+    }
+    /// Remove the name of a file under current inode,
+    /// and the file itself once its last name is gone and no handle is open on it
+    pub fn unlink(&self, name: &str) -> bool {
+        let mut fs = self.fs.lock();
+        let (position, inode_id) = match self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return None;
+            }
+            self.find_dirent(name, disk_inode, &mut fs)
+        }) {
+            Some(pair) => pair,
+            None => return false,
+        };
+        let inode = self.get_inode(inode_id, &fs);
+        if inode.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return false;
+        }
+        fs.begin();
+        self.remove_dirent(position, &mut fs);
+        let nlink = inode.modify_disk_inode(|disk_inode| {
+            disk_inode.nlink -= 1;
+            disk_inode.ctime = now();
+            disk_inode.nlink
+        });
+        fs.commit();
+        if nlink == 0 {
+            inode.release_unless_open(&mut fs);
+        }
+        true
+    }
+    /// Move what is named `old_name` under current inode to `new_name` under `new_dir`,
+    /// all in one transaction, but for freeing what is replaced.
+    /// What is at `new_name` already is replaced, if it is a file and so is the moved inode,
+    /// or if it is an empty directory and so is the moved inode.
+    /// A directory cannot be moved under itself.
+    pub fn rename(&self, old_name: &str, new_dir: &Inode, new_name: &str) -> bool {
+        let is_dots = |name: &str| name == "." || name == "..";
+        if is_dots(old_name) || is_dots(new_name) {
+            return false;
+        }
+        if new_name.is_empty() || new_name.len() > NAME_LENGTH_LIMIT {
+            return false;
+        }
+        let mut fs = self.fs.lock();
+        let (position, inode_id) = match self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return None;
+            }
+            self.find_dirent(old_name, disk_inode, &mut fs)
+        }) {
+            Some(pair) => pair,
+            None => return false,
+        };
+        if fs.orphans.contains(&new_dir.inode_id)
+            || !new_dir.read_disk_inode(|disk_inode| disk_inode.is_dir())
+        {
+            return false;
+        }
+        let inode = self.get_inode(inode_id, &fs);
+        let is_dir = inode.read_disk_inode(|disk_inode| disk_inode.is_dir());
+        if is_dir && new_dir.is_under(inode_id, &mut fs) {
+            return false;
+        }
+        let target = match new_dir.read_disk_inode(|disk_inode| {
+            new_dir.find_dirent(new_name, disk_inode, &mut fs)
+        }) {
+            // both names refer to the same inode already
+            Some((_, target_id)) if target_id == inode_id => return true,
+            Some((target_position, target_id)) => {
+                let target = self.get_inode(target_id, &fs);
+                let replaceable = target.read_disk_inode(|disk_inode| {
+                    if disk_inode.is_dir() {
+                        is_dir && target.is_empty_dir(disk_inode)
+                    } else {
+                        !is_dir
+                    }
+                });
+                if !replaceable {
+                    return false;
+                }
+                Some((target_position, target))
+            }
+            None => None,
+        };
+        fs.begin();
+        let released = match target {
+            Some((target_position, target)) => {
+                // the dirent is taken over in place, so the name never goes missing
+                new_dir.set_dirent_inode(target_position, inode_id);
+                let nlink = target.modify_disk_inode(|disk_inode| {
+                    disk_inode.nlink -= 1;
+                    disk_inode.ctime = now();
+                    disk_inode.nlink
+                });
+                (nlink == 0).then_some(target)
+            }
+            None => {
+                new_dir.append_dirent(new_name, inode_id, &mut fs);
+                None
+            }
+        };
+        self.remove_dirent(position, &mut fs);
+        if is_dir && new_dir.inode_id != self.inode_id {
+            // ".." follows the directory to its new parent
+            let parent_position = inode
+                .read_disk_inode(|disk_inode| inode.find_dirent("..", disk_inode, &mut fs))
+                .map(|(position, _)| position)
+                .unwrap();
+            inode.set_dirent_inode(parent_position, new_dir.inode_id);
+        }
+        inode.modify_disk_inode(|disk_inode| disk_inode.ctime = now());
+        fs.commit();
+        if let Some(target) = released {
+            target.release_unless_open(&mut fs);
+        }
+        true
+    }
     /// List inodes under current inode
     pub fn ls(&self) -> Vec<String> {
         let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
             let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(
-                        i * DIRENT_SZ,
-                        dirent.as_bytes_mut(),
-                        &self.block_device,
-                    ),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
+            for block in 0..disk_inode.size as usize / BLOCK_SZ {
+                for dirent in self.read_dir_block(block, disk_inode).entries() {
+                    if dirent.name != "." && dirent.name != ".." {
+                        v.push(String::from(dirent.name));
+                    }
+                }
             }
             v
         })
     }
+    /// Read the first dirent at or after `offset` bytes into current directory,
+    /// "." and ".." included
+    /// returns the dirent and the offset to read the next one from
+    pub fn read_dir(&self, offset: usize) -> Option<(Dirent, usize)> {
+        let fs = self.fs.lock();
+        let (inode_id, name, next) = self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return None;
+            }
+            let first_block = offset / BLOCK_SZ;
+            (first_block..disk_inode.size as usize / BLOCK_SZ).find_map(|block| {
+                let start = if block == first_block { offset % BLOCK_SZ } else { 0 };
+                let dir_block = self.read_dir_block(block, disk_inode);
+                let entries = dir_block.entries();
+                let i = entries.iter().position(|dirent| dirent.offset >= start)?;
+                let next = entries.get(i + 1).map_or(BLOCK_SZ, |dirent| dirent.offset);
+                Some((
+                    entries[i].inode_number,
+                    String::from(entries[i].name),
+                    block * BLOCK_SZ + next,
+                ))
+            })
+        })?;
+        // the inode may share its block with current inode,
+        // so it is only read once the block of current inode is let go
+        let is_dir = self.get_inode(inode_id, &fs).read_disk_inode(|disk_inode| disk_inode.is_dir());
+        Some((Dirent { inode_id, name, is_dir }, next))
+    }
     /// Read data from current inode
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
         let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            disk_inode.read_at(offset, buf, &self.block_device)
-        })
+        let (len, stale) = self.read_disk_inode(|disk_inode| {
+            (
+                disk_inode.read_at(offset, buf, &self.block_device),
+                disk_inode.atime < disk_inode.mtime.max(disk_inode.ctime),
+            )
+        });
+        // like relatime on Linux, only the first read after a change is recorded,
+        // which spares reads from writing the inode back every time
+        if stale {
+            self.modify_disk_inode(|disk_inode| disk_inode.atime = now());
+        }
+        len
     }
-    /// Write data to current inode
+    /// Write data to current inode, growing it if needed,
+    /// with the holes in between left to read as zeros.
+    /// The write stops short at a hole whose filling would rewrite too many extent blocks.
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        // files stop growing at the max size
+        let end = (offset + buf.len()).min(MAX_FILE_SIZE as usize);
+        if offset >= end {
+            return 0;
+        }
         let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
+        // fill the holes step by step, each step in a transaction of its own
+        let mut start = offset;
+        while start < end {
+            let step_end = end.min(start + GROW_STEP);
+            let grows = self.read_disk_inode(|disk_inode| {
+                step_end as u32 > disk_inode.size || self.has_holes(start, step_end, disk_inode)
+            });
+            if grows {
+                let shifted = self.read_disk_inode(|disk_inode| {
+                    disk_inode.shifted_extent_blocks((start / BLOCK_SZ) as u32, &self.block_device)
+                });
+                if shifted > SHIFTED_EXTENT_BLOCKS_MAX {
+                    break;
+                }
+                fs.begin();
+                self.modify_disk_inode(|disk_inode| {
+                    self.alloc_blocks(start, step_end, disk_inode, &mut fs);
+                    disk_inode.size = disk_inode.size.max(step_end as u32);
+                });
+                fs.commit();
+            }
+            start = step_end;
+        }
+        if start == offset {
+            return 0;
+        }
+        let buf = &buf[..start - offset];
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.set_modified(now());
             disk_inode.write_at(offset, buf, &self.block_device)
+        })
+    }
+    /// Write the data blocks and the disk inode of current inode back to disk,
+    /// along with the transactions committed so far,
+    /// which covers the dirents naming current inode and the bitmaps
+    pub fn fsync(&self) {
+        let fs = self.fs.lock();
+        fs.flush_journal();
+        let block_ids = self.read_disk_inode(|disk_inode| {
+            disk_inode.block_ids(&self.block_device)
         });
-        block_cache_sync_all();
-        size
+        for block_id in block_ids {
+            block_cache_sync(block_id as usize, &self.block_device);
+        }
+        block_cache_sync(self.block_id, &self.block_device);
     }
     /// Clear the data in current inode
     pub fn clear(&self) {
         let mut fs = self.fs.lock();
+        self.shrink(0, &mut fs);
+    }
+    /// Set the size of current inode, which must be a file no larger than the max size.
+    /// Shrinking frees the blocks past the new size,
+    /// growing leaves a hole which reads as zeros.
+    pub fn truncate(&self, new_size: u32) -> bool {
+        let mut fs = self.fs.lock();
+        if new_size > MAX_FILE_SIZE || self.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return false;
+        }
+        if new_size < self.read_disk_inode(|disk_inode| disk_inode.size) {
+            self.shrink(new_size, &mut fs);
+            return true;
+        }
+        fs.begin();
         self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
-            }
+            disk_inode.size = new_size;
+            disk_inode.set_modified(now());
         });
-        block_cache_sync_all();
+        fs.commit();
+        true
     }
 }
@@ -7,8 +7,14 @@ pub const MEMORY_END: usize = 0x88000000;
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 pub const MAX_SYSCALL_NUM: usize = 500;
+/// Number of disk blocks kept in the easy-fs block cache
+pub const BLOCK_CACHE_CAPACITY: usize = 64;
+/// Largest size a file in tmpfs may grow to
+pub const TMPFS_FILE_SIZE_MAX: usize = 0x100_0000;
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
 pub const CLOCK_FREQ: usize = 12500000;
-pub const MMIO: &[(usize, usize)] = &[(0x10001000, 0x1000)];
+/// The goldfish RTC of the QEMU virt machine, which keeps wall-clock time
+pub const RTC_BASE: usize = 0x101000;
+pub const MMIO: &[(usize, usize)] = &[(0x10001000, 0x1000), (RTC_BASE, 0x1000)];
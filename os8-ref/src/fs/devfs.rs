@@ -0,0 +1,216 @@
+//! Device files, in a filesystem mounted at `/dev`
+
+use super::vfs::{Dirent, FileSystem, VfsInode};
+use super::stdio::{console_input_waiting, console_try_getchar};
+use super::{File, PollEvents, Stat, StatMode};
+use crate::mm::UserBuffer;
+use crate::sbi::console_putchar;
+use crate::sync::UPSafeCell;
+use crate::task::suspend_current_and_run_next;
+use crate::timer::get_time;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::any::Any;
+use lazy_static::*;
+
+/// `/dev/null`, which reads as empty and swallows writes
+pub struct Null;
+/// `/dev/zero`, which reads as endless zeros and swallows writes
+pub struct Zero;
+/// `/dev/random`, which reads as pseudo-random bytes and swallows writes
+pub struct Random;
+/// `/dev/console`, the console for both reading and writing
+pub struct Console;
+
+/// The devices of devfs, by name
+const DEVICES: [&str; 4] = ["null", "zero", "random", "console"];
+
+/// Open the device file of the device numbered `index` in `DEVICES`
+fn open_device(index: usize) -> Arc<dyn File + Send + Sync> {
+    match DEVICES[index] {
+        "null" => Arc::new(Null),
+        "zero" => Arc::new(Zero),
+        "random" => Arc::new(Random),
+        _ => Arc::new(Console),
+    }
+}
+
+/// The filesystem of devices, a directory holding a node for each
+pub struct DevFs;
+
+/// The root directory of devfs
+struct DevDir;
+
+/// The node of the device numbered `.0` in `DEVICES`
+struct DevNode(usize);
+
+impl FileSystem for DevFs {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        Arc::new(DevDir)
+    }
+}
+
+impl VfsInode for DevDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn stat(&self) -> Stat {
+        let mut stat = Stat::with_mode(StatMode::DIR, 0o755);
+        stat.ino = 1;
+        stat
+    }
+    fn size(&self) -> usize {
+        0
+    }
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        let index = DEVICES.iter().position(|&device| device == name)?;
+        Some(Arc::new(DevNode(index)))
+    }
+    fn read_dir(&self, offset: usize) -> Option<(Dirent, usize)> {
+        // the offsets are the indices of ".", ".." and the devices in turn
+        let dirent = match offset {
+            0 | 1 => Dirent {
+                ino: 1,
+                name: String::from(if offset == 0 { "." } else { ".." }),
+                mode: StatMode::DIR,
+            },
+            _ => Dirent {
+                ino: offset as u64,
+                name: String::from(*DEVICES.get(offset - 2)?),
+                mode: StatMode::CHR,
+            },
+        };
+        Some((dirent, offset + 1))
+    }
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> usize {
+        0
+    }
+}
+
+impl VfsInode for DevNode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn stat(&self) -> Stat {
+        let mut stat = open_device(self.0).stat().unwrap();
+        stat.ino = self.0 as u64 + 2;
+        stat
+    }
+    fn size(&self) -> usize {
+        0
+    }
+    fn find(&self, _name: &str) -> Option<Arc<dyn VfsInode>> {
+        None
+    }
+    fn read_dir(&self, _offset: usize) -> Option<(Dirent, usize)> {
+        None
+    }
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> usize {
+        0
+    }
+    fn open(&self) -> Option<Arc<dyn File + Send + Sync>> {
+        Some(open_device(self.0))
+    }
+}
+
+lazy_static! {
+    /// The state of the xorshift64* generator behind `/dev/random`,
+    /// seeded from the timer on first use
+    static ref RANDOM_STATE: UPSafeCell<u64> = unsafe { UPSafeCell::new(get_time() as u64 | 1) };
+}
+
+/// Get the next pseudo-random number
+fn next_random() -> u64 {
+    let mut state = RANDOM_STATE.exclusive_access();
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+impl File for Null {
+    fn readable(&self) -> bool { true }
+    fn writable(&self) -> bool { true }
+    fn read(&self, _buf: UserBuffer) -> usize {
+        0
+    }
+    fn write(&self, buf: UserBuffer) -> usize {
+        buf.len()
+    }
+    fn stat(&self) -> Option<Stat> {
+        Some(Stat::with_mode(StatMode::CHR, 0o666))
+    }
+}
+
+impl File for Zero {
+    fn readable(&self) -> bool { true }
+    fn writable(&self) -> bool { true }
+    fn read(&self, mut buf: UserBuffer) -> usize {
+        for slice in buf.buffers.iter_mut() {
+            slice.fill(0);
+        }
+        buf.len()
+    }
+    fn write(&self, buf: UserBuffer) -> usize {
+        buf.len()
+    }
+    fn stat(&self) -> Option<Stat> {
+        Some(Stat::with_mode(StatMode::CHR, 0o666))
+    }
+}
+
+impl File for Random {
+    fn readable(&self) -> bool { true }
+    fn writable(&self) -> bool { true }
+    fn read(&self, mut buf: UserBuffer) -> usize {
+        for slice in buf.buffers.iter_mut() {
+            for chunk in slice.chunks_mut(8) {
+                let len = chunk.len();
+                chunk.copy_from_slice(&next_random().to_le_bytes()[..len]);
+            }
+        }
+        buf.len()
+    }
+    fn write(&self, buf: UserBuffer) -> usize {
+        buf.len()
+    }
+    fn stat(&self) -> Option<Stat> {
+        Some(Stat::with_mode(StatMode::CHR, 0o666))
+    }
+}
+
+impl File for Console {
+    fn readable(&self) -> bool { true }
+    fn writable(&self) -> bool { true }
+    /// Read a single character, waiting for one to come
+    fn read(&self, mut buf: UserBuffer) -> usize {
+        let slice = match buf.buffers.iter_mut().find(|slice| !slice.is_empty()) {
+            Some(slice) => slice,
+            None => return 0,
+        };
+        slice[0] = loop {
+            match console_try_getchar() {
+                Some(c) => break c,
+                None => suspend_current_and_run_next(),
+            }
+        };
+        1
+    }
+    fn write(&self, buf: UserBuffer) -> usize {
+        // byte by byte, as the bytes need not be UTF-8
+        // and a character may be split between two buffers
+        for buffer in buf.buffers.iter() {
+            buffer.iter().for_each(|&byte| console_putchar(byte as usize));
+        }
+        buf.len()
+    }
+    fn stat(&self) -> Option<Stat> {
+        Some(Stat::with_mode(StatMode::CHR, 0o620))
+    }
+    /// Ready to be read once a character is waiting, and always to be written
+    fn poll_ready(&self) -> PollEvents {
+        let mut events = PollEvents::OUT;
+        events.set(PollEvents::IN, console_input_waiting());
+        events
+    }
+}
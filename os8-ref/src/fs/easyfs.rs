@@ -0,0 +1,100 @@
+//! easy-fs on the block device, as a filesystem of the VFS
+
+use super::inode::{EFS, ROOT_INODE};
+use super::vfs::{Dirent, FileSystem, VfsInode};
+use super::{Stat, StatMode};
+use alloc::sync::Arc;
+use core::any::Any;
+use easy_fs::Inode;
+
+/// easy-fs on the block device, which is mounted at "/"
+pub struct EasyFs;
+
+impl FileSystem for EasyFs {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        ROOT_INODE.clone()
+    }
+    fn sync(&self) {
+        EFS.lock().sync();
+    }
+}
+
+impl VfsInode for Inode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn stat(&self) -> Stat {
+        let metadata = self.metadata();
+        let mode = if Inode::is_dir(self) { StatMode::DIR } else { StatMode::FILE };
+        let mut stat = Stat::with_mode(mode, metadata.perm as u32);
+        stat.ino = self.inode_id() as u64;
+        stat.nlink = self.nlink();
+        stat.uid = metadata.uid;
+        stat.gid = metadata.gid;
+        stat.atime = metadata.atime;
+        stat.mtime = metadata.mtime;
+        stat.ctime = metadata.ctime;
+        stat
+    }
+    fn is_dir(&self) -> bool {
+        Inode::is_dir(self)
+    }
+    fn size(&self) -> usize {
+        Inode::size(self) as usize
+    }
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        Inode::find(self, name).map(|inode| inode as Arc<dyn VfsInode>)
+    }
+    fn read_dir(&self, offset: usize) -> Option<(Dirent, usize)> {
+        Inode::read_dir(self, offset).map(|(dirent, next)| {
+            let dirent = Dirent {
+                ino: dirent.inode_id as u64,
+                name: dirent.name,
+                mode: if dirent.is_dir { StatMode::DIR } else { StatMode::FILE },
+            };
+            (dirent, next)
+        })
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        Inode::read_at(self, offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        Inode::write_at(self, offset, buf)
+    }
+    fn truncate(&self, size: usize) -> bool {
+        size <= u32::MAX as usize && Inode::truncate(self, size as u32)
+    }
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        Inode::create(self, name).map(|inode| inode as Arc<dyn VfsInode>)
+    }
+    fn mkdir(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        Inode::mkdir(self, name).map(|inode| inode as Arc<dyn VfsInode>)
+    }
+    fn link(&self, name: &str, inode: &dyn VfsInode) -> bool {
+        inode
+            .as_any()
+            .downcast_ref::<Inode>()
+            .map_or(false, |inode| Inode::link(self, name, inode))
+    }
+    fn unlink(&self, name: &str) -> bool {
+        Inode::unlink(self, name)
+    }
+    fn rmdir(&self, name: &str) -> bool {
+        Inode::rmdir(self, name)
+    }
+    fn rename(&self, old_name: &str, new_dir: &dyn VfsInode, new_name: &str) -> bool {
+        new_dir
+            .as_any()
+            .downcast_ref::<Inode>()
+            .map_or(false, |new_dir| Inode::rename(self, old_name, new_dir, new_name))
+    }
+    fn fsync(&self) {
+        Inode::fsync(self)
+    }
+    fn open_handle(&self) {
+        Inode::open_handle(self)
+    }
+    fn close_handle(&self) {
+        Inode::close_handle(self)
+    }
+}
@@ -3,26 +3,36 @@ use easy_fs::{
     Inode,
 };
 use crate::drivers::BLOCK_DEVICE;
+use crate::config::BLOCK_CACHE_CAPACITY;
+use crate::timer::get_epoch_sec;
 use crate::sync::UPSafeCell;
 use alloc::sync::Arc;
 use lazy_static::*;
 use bitflags::*;
+use spin::Mutex;
+use alloc::string::String;
 use alloc::vec::Vec;
-use super::File;
-use crate::mm::UserBuffer;
+use super::vfs::{covers_mount, lookup, same_mount, Dirent, VfsInode};
+use super::{File, PollEvents, Stat, StatMode, FIONREAD, SEEK_CUR, SEEK_END, SEEK_SET};
+use crate::mm::{translated_refmut, UserBuffer};
+use crate::task::current_user_token;
 
 /// A wrapper around a filesystem inode
 /// to implement File trait atop
 pub struct OSInode {
     readable: bool,
     writable: bool,
+    /// whether every write goes to the end
+    append: bool,
+    /// the absolute path the inode was opened at
+    path: String,
     inner: UPSafeCell<OSInodeInner>,
 }
 
 /// The OS inode inner in 'UPSafeCell'
 pub struct OSInodeInner {
     offset: usize,
-    inode: Arc<Inode>,
+    inode: Arc<dyn VfsInode>,
 }
 
 impl OSInode {
@@ -30,11 +40,16 @@ impl OSInode {
     pub fn new(
         readable: bool,
         writable: bool,
-        inode: Arc<Inode>,
+        append: bool,
+        path: String,
+        inode: Arc<dyn VfsInode>,
     ) -> Self {
+        inode.open_handle();
         Self {
             readable,
             writable,
+            append,
+            path,
             inner: unsafe { UPSafeCell::new(OSInodeInner {
                 offset: 0,
                 inode,
@@ -58,12 +73,20 @@ impl OSInode {
     }
 }
 
+impl Drop for OSInode {
+    fn drop(&mut self) {
+        self.inner.exclusive_access().inode.close_handle();
+    }
+}
+
 lazy_static! {
-    /// The root of all inodes, or '/' in short
-    pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-        Arc::new(EasyFileSystem::root_inode(&efs))
+    /// The filesystem on the block device
+    pub static ref EFS: Arc<Mutex<EasyFileSystem>> = {
+        easy_fs::set_clock(get_epoch_sec);
+        EasyFileSystem::open_with_cache(BLOCK_DEVICE.clone(), BLOCK_CACHE_CAPACITY)
     };
+    /// The root of all inodes, or '/' in short
+    pub static ref ROOT_INODE: Arc<Inode> = Arc::new(EasyFileSystem::root_inode(&EFS));
 }
 
 /// List all files in the filesystems
@@ -83,6 +106,7 @@ bitflags! {
         const RDWR = 1 << 1;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        const APPEND = 1 << 11;
     }
 }
 
@@ -101,69 +125,367 @@ impl OpenFlags {
     }
 }
 
-/// Open a file by path
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
-    let (readable, writable) = flags.read_write();
+/// Split a path into its parent directory and the last component
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Join `path` onto the absolute path `cwd`,
+/// resolving "." and ".." components along the way
+pub fn join_path(cwd: &str, path: &str) -> String {
+    let base = if path.starts_with('/') { "" } else { cwd };
+    let mut components: Vec<&str> = Vec::new();
+    for name in base.split('/').chain(path.split('/')) {
+        match name {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(name),
+        }
+    }
+    let mut joined = String::from("/");
+    joined.push_str(components.join("/").as_str());
+    joined
+}
+
+/// Find or create the inode to open at the absolute path `path`
+fn open_inode(path: &str, flags: OpenFlags) -> Option<Arc<dyn VfsInode>> {
+    let (_, writable) = flags.read_write();
     if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
-            // clear size
-            inode.clear();
-            Some(Arc::new(OSInode::new(
-                readable,
-                writable,
-                inode,
-            )))
+        if let Some(inode) = lookup(path) {
+            // directories cannot be truncated
+            if inode.is_dir() {
+                return None;
+            }
+            inode.truncate(0);
+            Some(inode)
         } else {
             // create file
-            ROOT_INODE.create(name)
-                .map(|inode| {
-                    Arc::new(OSInode::new(
-                        readable,
-                        writable,
-                        inode,
-                    ))
-                })
+            let (parent_path, name) = split_path(path);
+            find_dir(parent_path)
+                .filter(|_| !name.is_empty())
+                .and_then(|parent| parent.create(name))
         }
     } else {
-        ROOT_INODE.find(name)
+        lookup(path)
+            // directories can only be opened for reading, and never truncated
+            .filter(|inode| !(writable || flags.contains(OpenFlags::TRUNC)) || !inode.is_dir())
             .map(|inode| {
                 if flags.contains(OpenFlags::TRUNC) {
-                    inode.clear();
+                    inode.truncate(0);
                 }
-                Arc::new(OSInode::new(
-                    readable,
-                    writable,
-                    inode
-                ))
+                inode
             })
     }
 }
 
+/// Open a file by path, which is resolved from the root directory
+pub fn open_file(path: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+    let (readable, writable) = flags.read_write();
+    let path = join_path("/", path);
+    open_inode(path.as_str(), flags).map(|inode| {
+        Arc::new(OSInode::new(
+            readable,
+            writable,
+            flags.contains(OpenFlags::APPEND),
+            path,
+            inode,
+        ))
+    })
+}
+
+/// Open a file by path relative to the directory `dir`,
+/// which is opened as its own file if it is a device,
+/// limited to the access mode of `flags` either way
+pub fn open_file_at(dir: &str, path: &str, flags: OpenFlags) -> Option<Arc<dyn File + Send + Sync>> {
+    let (readable, writable) = flags.read_write();
+    let path = join_path(dir, path);
+    let inode = open_inode(path.as_str(), flags)?;
+    if let Some(file) = inode.open() {
+        return Some(Arc::new(AccessFile { readable, writable, file }));
+    }
+    Some(Arc::new(OSInode::new(
+        readable,
+        writable,
+        flags.contains(OpenFlags::APPEND),
+        path,
+        inode,
+    )))
+}
+
+/// Find a directory by path, which is resolved from the root directory
+pub fn find_dir(path: &str) -> Option<Arc<dyn VfsInode>> {
+    find_dir_at("/", path)
+}
+
+/// Find a directory by path relative to the directory `dir`
+pub fn find_dir_at(dir: &str, path: &str) -> Option<Arc<dyn VfsInode>> {
+    lookup(join_path(dir, path).as_str()).filter(|inode| inode.is_dir())
+}
+
+/// Create a directory by path relative to the directory `dir`
+pub fn mkdir_at(dir: &str, path: &str) -> bool {
+    let path = join_path(dir, path);
+    let (parent_path, name) = split_path(path.as_str());
+    find_dir(parent_path)
+        .filter(|_| !name.is_empty())
+        .and_then(|parent| parent.mkdir(name))
+        .is_some()
+}
+
+/// Create a hard link `new_path` relative to `new_dir`
+/// for the file `old_path` relative to `old_dir`, both in one filesystem
+pub fn link_at(old_dir: &str, old_path: &str, new_dir: &str, new_path: &str) -> bool {
+    let old_path = join_path(old_dir, old_path);
+    let new_path = join_path(new_dir, new_path);
+    if !same_mount(old_path.as_str(), new_path.as_str()) {
+        return false;
+    }
+    let inode = match lookup(old_path.as_str()) {
+        Some(inode) => inode,
+        None => return false,
+    };
+    let (parent_path, name) = split_path(new_path.as_str());
+    find_dir(parent_path)
+        .map_or(false, |parent| !name.is_empty() && parent.link(name, &*inode))
+}
+
+/// Move `old_path` relative to `old_dir` to `new_path` relative to `new_dir`,
+/// replacing what is there as `VfsInode::rename` allows,
+/// within one filesystem and with nothing mounted at or below either path
+pub fn rename_at(old_dir: &str, old_path: &str, new_dir: &str, new_path: &str) -> bool {
+    let old_path = join_path(old_dir, old_path);
+    let new_path = join_path(new_dir, new_path);
+    if !same_mount(old_path.as_str(), new_path.as_str())
+        || covers_mount(old_path.as_str())
+        || covers_mount(new_path.as_str())
+    {
+        return false;
+    }
+    let (old_parent_path, old_name) = split_path(old_path.as_str());
+    let (new_parent_path, new_name) = split_path(new_path.as_str());
+    match (find_dir(old_parent_path), find_dir(new_parent_path)) {
+        (Some(old_parent), Some(new_parent)) => old_parent.rename(old_name, &*new_parent, new_name),
+        _ => false,
+    }
+}
+
+/// Remove the file, or the empty directory if `remove_dir` is set,
+/// at `path` relative to the directory `dir`, unless something is mounted there
+pub fn unlink_at(dir: &str, path: &str, remove_dir: bool) -> bool {
+    let path = join_path(dir, path);
+    if covers_mount(path.as_str()) {
+        return false;
+    }
+    let (parent_path, name) = split_path(path.as_str());
+    find_dir(parent_path).map_or(false, |parent| {
+        if remove_dir {
+            parent.rmdir(name)
+        } else {
+            parent.unlink(name)
+        }
+    })
+}
+
+/// Read from `inode` at `offset` into `buf` until either runs out
+fn read_buffer(inode: &dyn VfsInode, mut offset: usize, buf: &mut UserBuffer) -> usize {
+    let mut total_read_size = 0usize;
+    for slice in buf.buffers.iter_mut() {
+        let read_size = inode.read_at(offset, *slice);
+        offset += read_size;
+        total_read_size += read_size;
+        if read_size < slice.len() {
+            break;
+        }
+    }
+    total_read_size
+}
+
+/// Write `buf` to `inode` at `offset`, stopping short at the max file size
+fn write_buffer(inode: &dyn VfsInode, mut offset: usize, buf: &UserBuffer) -> usize {
+    let mut total_write_size = 0usize;
+    for slice in buf.buffers.iter() {
+        let write_size = inode.write_at(offset, *slice);
+        offset += write_size;
+        total_write_size += write_size;
+        if write_size < slice.len() {
+            break;
+        }
+    }
+    total_write_size
+}
+
+/// `d_type` of pipes in `linux_dirent64`
+const DT_FIFO: u8 = 1;
+/// `d_type` of character devices in `linux_dirent64`
+const DT_CHR: u8 = 2;
+/// `d_type` of directories in `linux_dirent64`
+const DT_DIR: u8 = 4;
+/// `d_type` of regular files in `linux_dirent64`
+const DT_REG: u8 = 8;
+
+/// Encode a dirent as a `linux_dirent64` record,
+/// whose `d_off` is the offset to read the next dirent from
+fn dirent64(dirent: &Dirent, next: usize) -> Vec<u8> {
+    // d_ino, d_off, d_reclen and d_type, then the NUL-terminated name
+    let len = 8 + 8 + 2 + 1 + dirent.name.len() + 1;
+    let reclen = (len + 7) & !7;
+    let mut record = Vec::with_capacity(reclen);
+    record.extend_from_slice(&dirent.ino.to_le_bytes());
+    record.extend_from_slice(&(next as i64).to_le_bytes());
+    record.extend_from_slice(&(reclen as u16).to_le_bytes());
+    record.push(if dirent.mode.contains(StatMode::DIR) {
+        DT_DIR
+    } else if dirent.mode.contains(StatMode::CHR) {
+        DT_CHR
+    } else if dirent.mode.contains(StatMode::FIFO) {
+        DT_FIFO
+    } else {
+        DT_REG
+    });
+    record.extend_from_slice(dirent.name.as_bytes());
+    record.resize(reclen, 0);
+    record
+}
+
 impl File for OSInode {
     fn readable(&self) -> bool { self.readable }
     fn writable(&self) -> bool { self.writable }
+    fn inode(&self) -> Option<Arc<dyn VfsInode>> {
+        Some(self.inner.exclusive_access().inode.clone())
+    }
+    fn path(&self) -> Option<String> {
+        Some(self.path.clone())
+    }
     fn read(&self, mut buf: UserBuffer) -> usize {
         let mut inner = self.inner.exclusive_access();
-        let mut total_read_size = 0usize;
-        for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
-            if read_size == 0 {
-                break;
+        let read_size = read_buffer(&*inner.inode, inner.offset, &mut buf);
+        inner.offset += read_size;
+        read_size
+    }
+    fn write(&self, buf: UserBuffer) -> usize {
+        let mut inner = self.inner.exclusive_access();
+        if self.append {
+            inner.offset = inner.inode.size();
+        }
+        let write_size = write_buffer(&*inner.inode, inner.offset, &buf);
+        inner.offset += write_size;
+        write_size
+    }
+    fn stat(&self) -> Option<Stat> {
+        Some(self.inner.exclusive_access().inode.stat())
+    }
+    fn ioctl(&self, request: usize, arg: usize) -> Option<isize> {
+        match request {
+            FIONREAD => {
+                let inner = self.inner.exclusive_access();
+                let ready = inner.inode.size().saturating_sub(inner.offset);
+                *translated_refmut(current_user_token(), arg as *mut i32) = ready as i32;
+                Some(0)
             }
-            inner.offset += read_size;
-            total_read_size += read_size;
+            _ => None,
         }
-        total_read_size
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn seek(&self, offset: isize, whence: usize) -> Option<usize> {
+        let mut inner = self.inner.exclusive_access();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => inner.offset,
+            SEEK_END => inner.inode.size(),
+            _ => return None,
+        };
+        // seeking past the end is fine, writes there leave a hole behind
+        let offset = (base as isize).checked_add(offset).filter(|&offset| offset >= 0)?;
+        inner.offset = offset as usize;
+        Some(inner.offset)
+    }
+    fn read_at(&self, offset: usize, mut buf: UserBuffer) -> Option<usize> {
+        let inner = self.inner.exclusive_access();
+        Some(read_buffer(&*inner.inode, offset, &mut buf))
+    }
+    fn write_at(&self, offset: usize, buf: UserBuffer) -> Option<usize> {
+        let inner = self.inner.exclusive_access();
+        Some(write_buffer(&*inner.inode, offset, &buf))
+    }
+    fn read_dir(&self, mut buf: UserBuffer) -> Option<usize> {
         let mut inner = self.inner.exclusive_access();
-        let mut total_write_size = 0usize;
-        for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
-            inner.offset += write_size;
-            total_write_size += write_size;
+        if !inner.inode.is_dir() {
+            return None;
         }
-        total_write_size
+        // the offset of a directory is where its next dirent is read from
+        let mut records: Vec<u8> = Vec::new();
+        while let Some((dirent, next)) = inner.inode.read_dir(inner.offset) {
+            let record = dirent64(&dirent, next);
+            if records.len() + record.len() > buf.len() {
+                if records.is_empty() {
+                    return None;
+                }
+                break;
+            }
+            records.extend_from_slice(&record);
+            inner.offset = next;
+        }
+        let mut copied = 0usize;
+        for slice in buf.buffers.iter_mut() {
+            let len = slice.len().min(records.len() - copied);
+            slice[..len].copy_from_slice(&records[copied..copied + len]);
+            copied += len;
+        }
+        Some(records.len())
+    }
+}
+
+/// A file opened from an inode, such as a device,
+/// limited to the access mode it was opened with
+struct AccessFile {
+    readable: bool,
+    writable: bool,
+    file: Arc<dyn File + Send + Sync>,
+}
+
+impl File for AccessFile {
+    fn readable(&self) -> bool { self.readable && self.file.readable() }
+    fn writable(&self) -> bool { self.writable && self.file.writable() }
+    fn read(&self, buf: UserBuffer) -> usize {
+        if self.readable { self.file.read(buf) } else { 0 }
+    }
+    fn write(&self, buf: UserBuffer) -> usize {
+        if self.writable { self.file.write(buf) } else { 0 }
+    }
+    fn inode(&self) -> Option<Arc<dyn VfsInode>> {
+        self.file.inode()
+    }
+    fn path(&self) -> Option<String> {
+        self.file.path()
+    }
+    fn seek(&self, offset: isize, whence: usize) -> Option<usize> {
+        self.file.seek(offset, whence)
+    }
+    fn read_at(&self, offset: usize, buf: UserBuffer) -> Option<usize> {
+        if self.readable { self.file.read_at(offset, buf) } else { None }
+    }
+    fn write_at(&self, offset: usize, buf: UserBuffer) -> Option<usize> {
+        if self.writable { self.file.write_at(offset, buf) } else { None }
+    }
+    fn stat(&self) -> Option<Stat> {
+        self.file.stat()
+    }
+    fn ioctl(&self, request: usize, arg: usize) -> Option<isize> {
+        self.file.ioctl(request, arg)
+    }
+    fn poll_ready(&self) -> PollEvents {
+        let mut events = self.file.poll_ready();
+        events.set(PollEvents::IN, self.readable && events.contains(PollEvents::IN));
+        events.set(PollEvents::OUT, self.writable && events.contains(PollEvents::OUT));
+        events
+    }
+    fn read_dir(&self, buf: UserBuffer) -> Option<usize> {
+        if self.readable { self.file.read_dir(buf) } else { None }
     }
 }
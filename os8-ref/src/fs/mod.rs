@@ -1,8 +1,15 @@
 mod stdio;
 mod inode;
 mod pipe;
+mod devfs;
+mod easyfs;
+mod tmpfs;
+mod procfs;
+mod vfs;
 
 use crate::mm::UserBuffer;
+use alloc::string::String;
+use alloc::sync::Arc;
 
 /// The common abstraction of all IO resources
 pub trait File : Send + Sync {
@@ -10,8 +17,74 @@ pub trait File : Send + Sync {
     fn writable(&self) -> bool;
     fn read(&self, buf: UserBuffer) -> usize;
     fn write(&self, buf: UserBuffer) -> usize;
+    /// The filesystem inode behind this file, if there is one
+    fn inode(&self) -> Option<Arc<dyn VfsInode>> {
+        None
+    }
+    /// The absolute path this file was opened at, if it is in the filesystem
+    fn path(&self) -> Option<String> {
+        None
+    }
+    /// Move the offset to `offset` bytes past the start, the offset or the end,
+    /// as `whence` says, and return the new offset
+    /// or None if this cannot seek or the offset would be negative
+    fn seek(&self, _offset: isize, _whence: usize) -> Option<usize> {
+        None
+    }
+    /// Read at `offset` without moving the offset, or None if this cannot seek
+    fn read_at(&self, _offset: usize, _buf: UserBuffer) -> Option<usize> {
+        None
+    }
+    /// Write at `offset` without moving the offset, or None if this cannot seek
+    fn write_at(&self, _offset: usize, _buf: UserBuffer) -> Option<usize> {
+        None
+    }
+    /// The stat of this file, or None if it has none
+    fn stat(&self) -> Option<Stat> {
+        None
+    }
+    /// Carry out a device-specific `request` whose argument is `arg`,
+    /// or return None if this does not know the request
+    fn ioctl(&self, _request: usize, _arg: usize) -> Option<isize> {
+        None
+    }
+    /// The events this is ready for right now, those that would not block
+    fn poll_ready(&self) -> PollEvents {
+        let mut events = PollEvents::empty();
+        events.set(PollEvents::IN, self.readable());
+        events.set(PollEvents::OUT, self.writable());
+        events
+    }
+    /// Read the next entries of a directory into `buf` as `linux_dirent64` records
+    /// returns the bytes filled, or None if this is not a directory
+    /// or `buf` cannot hold the next entry
+    fn read_dir(&self, _buf: UserBuffer) -> Option<usize> {
+        None
+    }
 }
 
+/// `File::ioctl` request to get the number of bytes ready to be read into an `i32`
+pub const FIONREAD: usize = 0x541b;
+
+bitflags! {
+    /// Events a file may be ready for, as in `poll`
+    pub struct PollEvents: u16 {
+        /// reading would not block
+        const IN = 0x1;
+        /// writing would not block
+        const OUT = 0x4;
+        /// the other end of a pipe is closed
+        const HUP = 0x10;
+    }
+}
+
+/// `whence` of `File::seek` to seek from the start
+pub const SEEK_SET: usize = 0;
+/// `whence` of `File::seek` to seek from the offset
+pub const SEEK_CUR: usize = 1;
+/// `whence` of `File::seek` to seek from the end
+pub const SEEK_END: usize = 2;
+
 /// The stat of a inode
 #[repr(C)]
 #[derive(Debug)]
@@ -24,8 +97,43 @@ pub struct Stat {
     pub mode: StatMode,
     /// number of hard links
     pub nlink: u32,
+    /// permission bits, rwx for the owner, the group and others
+    pub perm: u32,
+    /// user id of the owner
+    pub uid: u32,
+    /// group id of the owner
+    pub gid: u32,
     /// unused pad
-    pad: [u64; 7],
+    pad0: u32,
+    /// time of last access, in seconds since the epoch
+    pub atime: u64,
+    /// time of last modification
+    pub mtime: u64,
+    /// time of last status change
+    pub ctime: u64,
+    /// unused pad
+    pad: [u64; 2],
+}
+
+impl Stat {
+    /// Build a stat of a single link with the given type and permissions,
+    /// the rest zeroed for filesystems to fill in as they know
+    pub fn with_mode(mode: StatMode, perm: u32) -> Self {
+        Self {
+            dev: 0,
+            ino: 0,
+            mode,
+            nlink: 1,
+            perm,
+            uid: 0,
+            gid: 0,
+            pad0: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            pad: [0; 2],
+        }
+    }
 }
 
 bitflags! {
@@ -33,6 +141,10 @@ bitflags! {
     /// whether a directory or a file
     pub struct StatMode: u32 {
         const NULL  = 0;
+        /// named pipe or pipe
+        const FIFO  = 0o010000;
+        /// character device
+        const CHR   = 0o020000;
         /// directory
         const DIR   = 0o040000;
         /// ordinary regular file
@@ -41,5 +153,7 @@ bitflags! {
 }    
 
 pub use stdio::{Stdin, Stdout};
-pub use inode::{OSInode, open_file, OpenFlags, list_apps};
+pub use inode::{OSInode, open_file, open_file_at, OpenFlags, list_apps};
+pub use inode::{find_dir, find_dir_at, join_path, link_at, mkdir_at, rename_at, unlink_at};
 pub use pipe::{Pipe, make_pipe};
+pub use vfs::{mount, new_filesystem, sync_all, umount, Dirent, FileSystem, VfsInode};
@@ -1,7 +1,8 @@
-use super::File;
+use super::{File, PollEvents, Stat, StatMode, FIONREAD};
 use alloc::sync::{Arc, Weak};
 use crate::sync::UPSafeCell;
-use crate::mm::UserBuffer;
+use crate::mm::{translated_refmut, UserBuffer};
+use crate::task::current_user_token;
 
 use crate::task::suspend_current_and_run_next;
 
@@ -176,4 +177,29 @@ impl File for Pipe {
             }
         }
     }
+    fn stat(&self) -> Option<Stat> {
+        Some(Stat::with_mode(StatMode::FIFO, 0o600))
+    }
+    fn ioctl(&self, request: usize, arg: usize) -> Option<isize> {
+        match request {
+            FIONREAD if self.readable => {
+                let ready = self.buffer.exclusive_access().available_read();
+                *translated_refmut(current_user_token(), arg as *mut i32) = ready as i32;
+                Some(0)
+            }
+            _ => None,
+        }
+    }
+    fn poll_ready(&self) -> PollEvents {
+        let ring_buffer = self.buffer.exclusive_access();
+        let mut events = PollEvents::empty();
+        if self.readable {
+            events.set(PollEvents::IN, ring_buffer.available_read() > 0);
+            events.set(PollEvents::HUP, ring_buffer.all_write_ends_closed());
+        }
+        if self.writable {
+            events.set(PollEvents::OUT, ring_buffer.available_write() > 0);
+        }
+        events
+    }
 }
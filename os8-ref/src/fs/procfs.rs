@@ -0,0 +1,368 @@
+//! The filesystem of kernel state, mounted at `/proc`
+//!
+//! Its files are generated from the kernel structures when they are opened,
+//! so a reader sees one snapshot however many reads it takes.
+
+use super::vfs::{Dirent, FileSystem, VfsInode};
+use super::{File, Stat, StatMode, SEEK_CUR, SEEK_END, SEEK_SET};
+use crate::config::PAGE_SIZE;
+use crate::mm::{frame_usage, MapPermission, UserBuffer};
+use crate::sync::UPSafeCell;
+use crate::task::{current_process, find_process, process_list, ProcessControlBlock, TaskStatus};
+use crate::timer::get_time_ms;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::Write;
+use easy_fs::block_cache_stats;
+
+/// The files about the whole kernel, in the root directory
+const INFO_FILES: [&str; 3] = ["meminfo", "uptime", "blockcache"];
+/// The files in the directory of each process
+const PID_FILES: [&str; 4] = ["status", "threads", "fds", "maps"];
+/// The offset of the first process in the root directory,
+/// after ".", "..", the info files and "self"
+const FIRST_PID_OFFSET: usize = INFO_FILES.len() + 3;
+
+/// The filesystem of kernel state
+pub struct ProcFs;
+
+/// A node of procfs
+#[derive(Clone, Copy)]
+enum ProcNode {
+    Root,
+    /// the file numbered `.0` in `INFO_FILES`
+    Info(usize),
+    /// the directory of the process of pid `.0`
+    Pid(usize),
+    /// the file numbered `.1` in `PID_FILES` of the process of pid `.0`
+    PidFile(usize, usize),
+}
+
+impl ProcNode {
+    fn ino(&self) -> u64 {
+        match *self {
+            ProcNode::Root => 1,
+            ProcNode::Info(index) => index as u64 + 2,
+            ProcNode::Pid(pid) => (pid as u64 + 1) << 3,
+            ProcNode::PidFile(pid, index) => ((pid as u64 + 1) << 3) | (index as u64 + 1),
+        }
+    }
+    fn mode(&self) -> StatMode {
+        match self {
+            ProcNode::Root | ProcNode::Pid(_) => StatMode::DIR,
+            _ => StatMode::FILE,
+        }
+    }
+    fn dirent(&self, name: String) -> Dirent {
+        Dirent { ino: self.ino(), name, mode: self.mode() }
+    }
+    /// The contents of this file as of now,
+    /// or None if this is a directory or its process is gone
+    fn generate(&self) -> Option<String> {
+        match *self {
+            ProcNode::Info(index) => Some(match INFO_FILES[index] {
+                "meminfo" => meminfo(),
+                "uptime" => uptime(),
+                _ => blockcache(),
+            }),
+            ProcNode::PidFile(pid, index) => {
+                let process = find_process(pid)?;
+                Some(match PID_FILES[index] {
+                    "status" => status(&process),
+                    "threads" => threads(&process),
+                    "fds" => fds(&process),
+                    _ => maps(&process),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        Arc::new(ProcInode(ProcNode::Root))
+    }
+}
+
+/// The inode of a procfs node
+struct ProcInode(ProcNode);
+
+impl VfsInode for ProcInode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn stat(&self) -> Stat {
+        let perm = if self.0.mode() == StatMode::DIR { 0o555 } else { 0o444 };
+        let mut stat = Stat::with_mode(self.0.mode(), perm);
+        stat.ino = self.0.ino();
+        stat
+    }
+    fn size(&self) -> usize {
+        // like Linux, the size of a generated file is not known before it is read
+        0
+    }
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        let node = match self.0 {
+            ProcNode::Root => {
+                if let Some(index) = INFO_FILES.iter().position(|&file| file == name) {
+                    ProcNode::Info(index)
+                } else if name == "self" {
+                    ProcNode::Pid(current_process().getpid())
+                } else {
+                    let pid = name.parse::<usize>().ok()?;
+                    find_process(pid)?;
+                    ProcNode::Pid(pid)
+                }
+            }
+            ProcNode::Pid(pid) => {
+                ProcNode::PidFile(pid, PID_FILES.iter().position(|&file| file == name)?)
+            }
+            _ => return None,
+        };
+        Some(Arc::new(ProcInode(node)))
+    }
+    fn read_dir(&self, offset: usize) -> Option<(Dirent, usize)> {
+        if offset < 2 {
+            let name = String::from(if offset == 0 { "." } else { ".." });
+            return Some((self.0.dirent(name), offset + 1));
+        }
+        match self.0 {
+            ProcNode::Root => {
+                if let Some(&file) = INFO_FILES.get(offset - 2) {
+                    let dirent = ProcNode::Info(offset - 2).dirent(String::from(file));
+                    return Some((dirent, offset + 1));
+                }
+                if offset == FIRST_PID_OFFSET - 1 {
+                    let dirent = ProcNode::Pid(current_process().getpid()).dirent(String::from("self"));
+                    return Some((dirent, offset + 1));
+                }
+                // the offset past "self" is the pid to go on from,
+                // which stays put however the processes come and go
+                let pid = process_list()
+                    .iter()
+                    .map(|process| process.getpid())
+                    .find(|&pid| pid >= offset - FIRST_PID_OFFSET)?;
+                let dirent = ProcNode::Pid(pid).dirent(format!("{}", pid));
+                Some((dirent, FIRST_PID_OFFSET + pid + 1))
+            }
+            ProcNode::Pid(pid) => {
+                let index = offset - 2;
+                let dirent = ProcNode::PidFile(pid, index).dirent(String::from(*PID_FILES.get(index)?));
+                Some((dirent, offset + 1))
+            }
+            _ => None,
+        }
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let data = self.0.generate().unwrap_or_default();
+        let data = data.as_bytes();
+        if offset >= data.len() {
+            return 0;
+        }
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        len
+    }
+    fn open(&self) -> Option<Arc<dyn File + Send + Sync>> {
+        let data = self.0.generate()?;
+        Some(Arc::new(ProcFile {
+            ino: self.0.ino(),
+            data: data.into_bytes(),
+            offset: unsafe { UPSafeCell::new(0) },
+        }))
+    }
+}
+
+/// An opened procfs file, holding what it was generated as
+struct ProcFile {
+    ino: u64,
+    data: Vec<u8>,
+    offset: UPSafeCell<usize>,
+}
+
+impl ProcFile {
+    /// Copy the data at `offset` into `buf` and return the bytes copied
+    fn copy_out(&self, mut offset: usize, buf: &mut UserBuffer) -> usize {
+        let mut total = 0usize;
+        for slice in buf.buffers.iter_mut() {
+            if offset >= self.data.len() {
+                break;
+            }
+            let len = slice.len().min(self.data.len() - offset);
+            slice[..len].copy_from_slice(&self.data[offset..offset + len]);
+            offset += len;
+            total += len;
+        }
+        total
+    }
+}
+
+impl File for ProcFile {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn writable(&self) -> bool {
+        false
+    }
+    fn read(&self, mut buf: UserBuffer) -> usize {
+        let mut offset = self.offset.exclusive_access();
+        let read_size = self.copy_out(*offset, &mut buf);
+        *offset += read_size;
+        read_size
+    }
+    fn write(&self, _buf: UserBuffer) -> usize {
+        0
+    }
+    fn seek(&self, offset: isize, whence: usize) -> Option<usize> {
+        let mut current = self.offset.exclusive_access();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => *current,
+            SEEK_END => self.data.len(),
+            _ => return None,
+        };
+        let offset = (base as isize).checked_add(offset).filter(|&offset| offset >= 0)?;
+        *current = offset as usize;
+        Some(*current)
+    }
+    fn read_at(&self, offset: usize, mut buf: UserBuffer) -> Option<usize> {
+        Some(self.copy_out(offset, &mut buf))
+    }
+    fn stat(&self) -> Option<Stat> {
+        let mut stat = Stat::with_mode(StatMode::FILE, 0o444);
+        stat.ino = self.ino;
+        Some(stat)
+    }
+}
+
+/// `/proc/meminfo`, the physical frames in kB
+fn meminfo() -> String {
+    let usage = frame_usage();
+    let kb = |frames: usize| frames * PAGE_SIZE / 1024;
+    format!(
+        "MemTotal: {} kB\nMemFree: {} kB\nMemUsed: {} kB\n",
+        kb(usage.total),
+        kb(usage.free),
+        kb(usage.total - usage.free)
+    )
+}
+
+/// `/proc/uptime`, the seconds since boot
+fn uptime() -> String {
+    let ms = get_time_ms();
+    format!("{}.{:02}\n", ms / 1000, ms % 1000 / 10)
+}
+
+/// `/proc/blockcache`, the counters of the easy-fs block cache
+fn blockcache() -> String {
+    let stats = block_cache_stats();
+    format!(
+        "hits: {}\nmisses: {}\nwritebacks: {}\n",
+        stats.hits, stats.misses, stats.writebacks
+    )
+}
+
+fn task_status_name(status: TaskStatus) -> &'static str {
+    match status {
+        TaskStatus::UnInit => "uninit",
+        TaskStatus::Ready => "ready",
+        TaskStatus::Running => "running",
+        TaskStatus::Blocking => "blocking",
+    }
+}
+
+/// `/proc/<pid>/status`
+fn status(process: &ProcessControlBlock) -> String {
+    let inner = process.inner_exclusive_access();
+    let ppid = inner
+        .parent
+        .as_ref()
+        .and_then(|parent| parent.upgrade())
+        .map_or(0, |parent| parent.getpid());
+    // a process is as far along as the furthest of its threads
+    let state = if inner.is_zombie {
+        "zombie"
+    } else {
+        let statuses: Vec<TaskStatus> = inner
+            .tasks
+            .iter()
+            .flatten()
+            .map(|task| task.inner_exclusive_access().task_status)
+            .collect();
+        [TaskStatus::Running, TaskStatus::Ready, TaskStatus::Blocking]
+            .iter()
+            .find(|status| statuses.contains(status))
+            .map_or("uninit", |&status| task_status_name(status))
+    };
+    let mut text = format!(
+        "Pid: {}\nPPid: {}\nState: {}\nThreads: {}\nCwd: {}\n",
+        process.getpid(),
+        ppid,
+        state,
+        inner.tasks.iter().flatten().count(),
+        inner.cwd
+    );
+    if inner.is_zombie {
+        writeln!(text, "ExitCode: {}", inner.exit_code).unwrap();
+    }
+    text
+}
+
+/// `/proc/<pid>/threads`, a line of the tid, the status and the exit code of each thread
+fn threads(process: &ProcessControlBlock) -> String {
+    let inner = process.inner_exclusive_access();
+    let mut text = String::new();
+    for (tid, task) in inner.tasks.iter().enumerate() {
+        if let Some(task) = task {
+            let task_inner = task.inner_exclusive_access();
+            let exit_code = task_inner.exit_code.map_or(String::from("-"), |code| format!("{}", code));
+            writeln!(text, "{} {} {}", tid, task_status_name(task_inner.task_status), exit_code).unwrap();
+        }
+    }
+    text
+}
+
+/// `/proc/<pid>/fds`, a line of the number, the type and the path of each open file
+fn fds(process: &ProcessControlBlock) -> String {
+    let fd_table = process.inner_exclusive_access().fd_table.clone();
+    let mut text = String::new();
+    for (fd, file) in fd_table.iter().enumerate() {
+        if let Some(file) = file {
+            let kind = match file.stat().map(|stat| stat.mode) {
+                Some(StatMode::DIR) => "dir",
+                Some(StatMode::FILE) => "file",
+                Some(StatMode::CHR) => "chr",
+                Some(StatMode::FIFO) => "fifo",
+                _ => "-",
+            };
+            let path = file.path().unwrap_or_else(|| String::from("-"));
+            writeln!(text, "{} {} {}", fd, kind, path).unwrap();
+        }
+    }
+    text
+}
+
+/// `/proc/<pid>/maps`, a line of the range and the permission of each area
+fn maps(process: &ProcessControlBlock) -> String {
+    let inner = process.inner_exclusive_access();
+    let mut text = String::new();
+    for (start, end, perm) in inner.memory_set.area_list() {
+        let flag = |bit: MapPermission, c: char| if perm.contains(bit) { c } else { '-' };
+        writeln!(
+            text,
+            "{:#x}-{:#x} {}{}{}{}",
+            start.0,
+            end.0,
+            flag(MapPermission::R, 'r'),
+            flag(MapPermission::W, 'w'),
+            flag(MapPermission::X, 'x'),
+            flag(MapPermission::U, 'u')
+        )
+        .unwrap();
+    }
+    text
+}
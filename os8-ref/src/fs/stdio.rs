@@ -1,36 +1,70 @@
-use super::File;
+use super::{File, PollEvents, Stat, StatMode};
 use crate::mm::{UserBuffer};
-use crate::sbi::console_getchar;
+use crate::sbi::{console_getchar, console_putchar};
+use crate::sync::UPSafeCell;
 use crate::task::suspend_current_and_run_next;
+use lazy_static::*;
 
 /// The standard input
 pub struct Stdin;
 /// The standard output
 pub struct Stdout;
 
+lazy_static! {
+    /// A character taken from the console to see whether input is waiting,
+    /// which the next read gets
+    static ref PENDING: UPSafeCell<Option<u8>> = unsafe { UPSafeCell::new(None) };
+}
+
+/// Get a character from the console without waiting, or None if none has come
+fn getchar_nowait() -> Option<u8> {
+    match console_getchar() {
+        0 => None,
+        c => Some(c as u8),
+    }
+}
+
+/// Take the character waiting on the console, or None if none is waiting
+pub fn console_try_getchar() -> Option<u8> {
+    PENDING.exclusive_access().take().or_else(getchar_nowait)
+}
+
+/// Whether a character is waiting on the console, leaving it there
+pub fn console_input_waiting() -> bool {
+    let mut pending = PENDING.exclusive_access();
+    if pending.is_none() {
+        *pending = getchar_nowait();
+    }
+    pending.is_some()
+}
+
 impl File for Stdin {
     fn readable(&self) -> bool { true }
     fn writable(&self) -> bool { false }
     fn read(&self, mut user_buf: UserBuffer) -> usize {
         assert_eq!(user_buf.len(), 1);
         // busy loop
-        let mut c: usize;
-        loop {
-            c = console_getchar();
-            if c == 0 {
-                suspend_current_and_run_next();
-                continue;
-            } else {
-                break;
+        let ch = loop {
+            match console_try_getchar() {
+                Some(ch) => break ch,
+                None => suspend_current_and_run_next(),
             }
-        }
-        let ch = c as u8;
+        };
         unsafe { user_buf.buffers[0].as_mut_ptr().write_volatile(ch); }
         1
     }
     fn write(&self, _user_buf: UserBuffer) -> usize {
         panic!("Cannot write to stdin!");
     }
+    fn stat(&self) -> Option<Stat> {
+        Some(Stat::with_mode(StatMode::CHR, 0o620))
+    }
+    /// Ready to be read once a character is waiting, as reads wait for one
+    fn poll_ready(&self) -> PollEvents {
+        let mut events = PollEvents::empty();
+        events.set(PollEvents::IN, console_input_waiting());
+        events
+    }
 }
 
 impl File for Stdout {
@@ -40,9 +74,13 @@ impl File for Stdout {
         panic!("Cannot read from stdout!");
     }
     fn write(&self, user_buf: UserBuffer) -> usize {
+        // raw bytes, which a page boundary may split mid-character
         for buffer in user_buf.buffers.iter() {
-            print!("{}", core::str::from_utf8(*buffer).unwrap());
+            buffer.iter().for_each(|&byte| console_putchar(byte as usize));
         }
         user_buf.len()
     }
+    fn stat(&self) -> Option<Stat> {
+        Some(Stat::with_mode(StatMode::CHR, 0o620))
+    }
 }
@@ -0,0 +1,430 @@
+//! A filesystem in memory, whose files are kept in frames
+//! and are gone once it is unmounted or the system shuts down
+
+use super::vfs::{Dirent, FileSystem, VfsInode};
+use super::{Stat, StatMode};
+use crate::config::{PAGE_SIZE, TMPFS_FILE_SIZE_MAX};
+use crate::mm::{frame_alloc, FrameTracker};
+use crate::sync::UPSafeCell;
+use crate::timer::get_epoch_sec;
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A filesystem in memory
+pub struct TmpFs {
+    root: Arc<TmpNode>,
+    /// The inode number to give the next inode
+    next_ino: Arc<AtomicU64>,
+}
+
+/// An inode of tmpfs
+struct TmpNode {
+    ino: u64,
+    inner: UPSafeCell<TmpNodeInner>,
+}
+
+struct TmpNodeInner {
+    perm: u16,
+    nlink: u32,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    data: TmpData,
+}
+
+/// What an inode of tmpfs holds
+enum TmpData {
+    /// The pages of a file by index, missing for the holes which read as zeros
+    File {
+        size: usize,
+        pages: BTreeMap<usize, FrameTracker>,
+    },
+    /// The entries of a directory, in slots which keep their offsets
+    /// as the entries before them are removed, and its parent,
+    /// which is gone for the root
+    Dir {
+        parent: Weak<TmpNode>,
+        entries: Vec<Option<(String, Arc<TmpNode>)>>,
+    },
+}
+
+/// A handle on an inode of tmpfs, as the VFS sees it
+struct TmpInode {
+    node: Arc<TmpNode>,
+    next_ino: Arc<AtomicU64>,
+}
+
+impl TmpFs {
+    /// Create an empty tmpfs
+    pub fn new() -> Self {
+        let next_ino = Arc::new(AtomicU64::new(1));
+        let root = TmpNode::new(&next_ino, TmpData::Dir { parent: Weak::new(), entries: Vec::new() });
+        Self { root, next_ino }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        Arc::new(TmpInode {
+            node: self.root.clone(),
+            next_ino: self.next_ino.clone(),
+        })
+    }
+}
+
+impl TmpNode {
+    /// Create an inode, a directory with two links or a file with one
+    fn new(next_ino: &AtomicU64, data: TmpData) -> Arc<Self> {
+        let now = get_epoch_sec();
+        let (perm, nlink) = match data {
+            TmpData::Dir { .. } => (0o755, 2),
+            TmpData::File { .. } => (0o644, 1),
+        };
+        Arc::new(Self {
+            ino: next_ino.fetch_add(1, Ordering::Relaxed),
+            inner: unsafe {
+                UPSafeCell::new(TmpNodeInner {
+                    perm,
+                    nlink,
+                    atime: now,
+                    mtime: now,
+                    ctime: now,
+                    data,
+                })
+            },
+        })
+    }
+    fn is_dir(&self) -> bool {
+        matches!(self.inner.exclusive_access().data, TmpData::Dir { .. })
+    }
+    /// Whether this is a directory holding nothing
+    fn is_empty_dir(&self) -> bool {
+        match &self.inner.exclusive_access().data {
+            TmpData::Dir { entries, .. } => entries.iter().all(|entry| entry.is_none()),
+            TmpData::File { .. } => false,
+        }
+    }
+    /// Find an entry of this directory by name
+    /// returns the slot of the entry and its inode
+    fn find(&self, name: &str) -> Option<(usize, Arc<TmpNode>)> {
+        match &self.inner.exclusive_access().data {
+            TmpData::Dir { entries, .. } => entries.iter().enumerate().find_map(|(slot, entry)| {
+                entry
+                    .as_ref()
+                    .filter(|(entry_name, _)| entry_name == name)
+                    .map(|(_, node)| (slot, node.clone()))
+            }),
+            TmpData::File { .. } => None,
+        }
+    }
+    /// Put an entry into the first free slot of this directory
+    fn insert(&self, name: &str, node: Arc<TmpNode>) {
+        let mut inner = self.inner.exclusive_access();
+        if let TmpData::Dir { entries, .. } = &mut inner.data {
+            let entry = Some((String::from(name), node));
+            match entries.iter().position(|entry| entry.is_none()) {
+                Some(slot) => entries[slot] = entry,
+                None => entries.push(entry),
+            }
+        }
+        let now = get_epoch_sec();
+        inner.mtime = now;
+        inner.ctime = now;
+    }
+    /// Take the entry out of a slot of this directory
+    fn remove(&self, slot: usize) {
+        let mut inner = self.inner.exclusive_access();
+        if let TmpData::Dir { entries, .. } = &mut inner.data {
+            entries[slot] = None;
+        }
+        let now = get_epoch_sec();
+        inner.mtime = now;
+        inner.ctime = now;
+    }
+    /// Add `delta` to the number of links
+    fn add_links(&self, delta: i32) {
+        let mut inner = self.inner.exclusive_access();
+        inner.nlink = (inner.nlink as i32 + delta) as u32;
+        inner.ctime = get_epoch_sec();
+    }
+    /// Whether this directory is `dir` or under it
+    fn is_under(self: &Arc<Self>, dir: &Arc<TmpNode>) -> bool {
+        let mut node = self.clone();
+        loop {
+            if Arc::ptr_eq(&node, dir) {
+                return true;
+            }
+            let parent = match &node.inner.exclusive_access().data {
+                TmpData::Dir { parent, .. } => parent.upgrade(),
+                TmpData::File { .. } => None,
+            };
+            match parent {
+                Some(parent) => node = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+impl TmpInode {
+    /// Wrap another inode of the same tmpfs
+    fn wrap(&self, node: Arc<TmpNode>) -> Arc<dyn VfsInode> {
+        Arc::new(TmpInode {
+            node,
+            next_ino: self.next_ino.clone(),
+        })
+    }
+    /// Create a file or a directory under this directory
+    fn create_node(&self, name: &str, data: TmpData) -> Option<Arc<dyn VfsInode>> {
+        if !valid_name(name) || !self.node.is_dir() || self.node.find(name).is_some() {
+            return None;
+        }
+        let node = TmpNode::new(&self.next_ino, data);
+        if node.is_dir() {
+            self.node.add_links(1);
+        }
+        self.node.insert(name, node.clone());
+        Some(self.wrap(node))
+    }
+}
+
+/// Whether `name` can name an entry of a directory
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+impl VfsInode for TmpInode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn stat(&self) -> Stat {
+        let inner = self.node.inner.exclusive_access();
+        let mode = match inner.data {
+            TmpData::Dir { .. } => StatMode::DIR,
+            TmpData::File { .. } => StatMode::FILE,
+        };
+        let mut stat = Stat::with_mode(mode, inner.perm as u32);
+        stat.ino = self.node.ino;
+        stat.nlink = inner.nlink;
+        stat.atime = inner.atime;
+        stat.mtime = inner.mtime;
+        stat.ctime = inner.ctime;
+        stat
+    }
+    fn is_dir(&self) -> bool {
+        self.node.is_dir()
+    }
+    fn size(&self) -> usize {
+        match &self.node.inner.exclusive_access().data {
+            TmpData::File { size, .. } => *size,
+            TmpData::Dir { .. } => 0,
+        }
+    }
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.node.find(name).map(|(_, node)| self.wrap(node))
+    }
+    fn read_dir(&self, offset: usize) -> Option<(Dirent, usize)> {
+        let inner = self.node.inner.exclusive_access();
+        let (parent, entries) = match &inner.data {
+            TmpData::Dir { parent, entries } => (parent, entries),
+            TmpData::File { .. } => return None,
+        };
+        // the offsets are 0 for ".", 1 for ".." and the slots after
+        let dirent = |name: &str, node: &TmpNode, mode: StatMode| Dirent {
+            ino: node.ino,
+            name: String::from(name),
+            mode,
+        };
+        match offset {
+            0 => Some((dirent(".", &self.node, StatMode::DIR), 1)),
+            1 => {
+                let parent = parent.upgrade().unwrap_or_else(|| self.node.clone());
+                Some((dirent("..", &parent, StatMode::DIR), 2))
+            }
+            _ => entries
+                .iter()
+                .enumerate()
+                .skip(offset - 2)
+                .find_map(|(slot, entry)| {
+                    let (name, node) = entry.as_ref()?;
+                    let mode = if node.is_dir() { StatMode::DIR } else { StatMode::FILE };
+                    Some((dirent(name, node, mode), slot + 3))
+                }),
+        }
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut inner = self.node.inner.exclusive_access();
+        let (size, pages) = match &inner.data {
+            TmpData::File { size, pages } => (*size, pages),
+            TmpData::Dir { .. } => return 0,
+        };
+        let end = offset.saturating_add(buf.len()).min(size);
+        let mut read = 0usize;
+        while offset + read < end {
+            let pos = offset + read;
+            let page_offset = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - page_offset).min(end - pos);
+            let dst = &mut buf[read..read + len];
+            match pages.get(&(pos / PAGE_SIZE)) {
+                Some(frame) => dst.copy_from_slice(&frame.ppn.get_bytes_array()[page_offset..page_offset + len]),
+                None => dst.fill(0),
+            }
+            read += len;
+        }
+        inner.atime = get_epoch_sec();
+        read
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let mut inner = self.node.inner.exclusive_access();
+        let (size, pages) = match &mut inner.data {
+            TmpData::File { size, pages } => (size, pages),
+            TmpData::Dir { .. } => return 0,
+        };
+        // writes stop short at the largest size a file may have, or once memory runs out
+        let total = offset.saturating_add(buf.len()).min(TMPFS_FILE_SIZE_MAX).saturating_sub(offset);
+        let mut written = 0usize;
+        while written < total {
+            let pos = offset + written;
+            let (index, page_offset) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            let frame = match pages.entry(index) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match frame_alloc() {
+                    Some(frame) => entry.insert(frame),
+                    None => break,
+                },
+            };
+            let len = (PAGE_SIZE - page_offset).min(total - written);
+            let bytes = frame.ppn.get_bytes_array();
+            bytes[page_offset..page_offset + len].copy_from_slice(&buf[written..written + len]);
+            written += len;
+        }
+        if written > 0 {
+            *size = (*size).max(offset + written);
+            let now = get_epoch_sec();
+            inner.mtime = now;
+            inner.ctime = now;
+        }
+        written
+    }
+    fn truncate(&self, new_size: usize) -> bool {
+        if new_size > TMPFS_FILE_SIZE_MAX {
+            return false;
+        }
+        let mut inner = self.node.inner.exclusive_access();
+        let (size, pages) = match &mut inner.data {
+            TmpData::File { size, pages } => (size, pages),
+            TmpData::Dir { .. } => return false,
+        };
+        if new_size < *size {
+            let kept = (new_size + PAGE_SIZE - 1) / PAGE_SIZE;
+            pages.retain(|&index, _| index < kept);
+            // the tail of the last page would show again if the file grew back
+            if let Some(frame) = pages.get(&(new_size / PAGE_SIZE)) {
+                frame.ppn.get_bytes_array()[new_size % PAGE_SIZE..].fill(0);
+            }
+        }
+        *size = new_size;
+        let now = get_epoch_sec();
+        inner.mtime = now;
+        inner.ctime = now;
+        true
+    }
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.create_node(name, TmpData::File { size: 0, pages: BTreeMap::new() })
+    }
+    fn mkdir(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.create_node(
+            name,
+            TmpData::Dir { parent: Arc::downgrade(&self.node), entries: Vec::new() },
+        )
+    }
+    fn link(&self, name: &str, inode: &dyn VfsInode) -> bool {
+        let node = match inode.as_any().downcast_ref::<TmpInode>() {
+            Some(inode) => inode.node.clone(),
+            None => return false,
+        };
+        // directories cannot be linked
+        if !valid_name(name) || node.is_dir() || !self.node.is_dir() || self.node.find(name).is_some() {
+            return false;
+        }
+        node.add_links(1);
+        self.node.insert(name, node);
+        true
+    }
+    fn unlink(&self, name: &str) -> bool {
+        match self.node.find(name) {
+            Some((slot, node)) if !node.is_dir() => {
+                self.node.remove(slot);
+                // the pages go along with the last handle on the inode
+                node.add_links(-1);
+                true
+            }
+            _ => false,
+        }
+    }
+    fn rmdir(&self, name: &str) -> bool {
+        if !valid_name(name) {
+            return false;
+        }
+        match self.node.find(name) {
+            Some((slot, node)) if node.is_empty_dir() => {
+                self.node.remove(slot);
+                self.node.add_links(-1);
+                node.add_links(-2);
+                true
+            }
+            _ => false,
+        }
+    }
+    fn rename(&self, old_name: &str, new_dir: &dyn VfsInode, new_name: &str) -> bool {
+        let new_dir = match new_dir.as_any().downcast_ref::<TmpInode>() {
+            Some(new_dir) if new_dir.node.is_dir() => new_dir.node.clone(),
+            _ => return false,
+        };
+        if !valid_name(old_name) || !valid_name(new_name) {
+            return false;
+        }
+        let (slot, node) = match self.node.find(old_name) {
+            Some(found) => found,
+            None => return false,
+        };
+        let is_dir = node.is_dir();
+        // a directory cannot be moved under itself
+        if is_dir && new_dir.is_under(&node) {
+            return false;
+        }
+        let target = new_dir.find(new_name);
+        if let Some((target_slot, target)) = target {
+            if Arc::ptr_eq(&target, &node) {
+                return true;
+            }
+            // files replace files, and directories replace empty directories
+            let replaceable = if is_dir { target.is_empty_dir() } else { !target.is_dir() };
+            if !replaceable {
+                return false;
+            }
+            new_dir.remove(target_slot);
+            if is_dir {
+                new_dir.add_links(-1);
+                target.add_links(-2);
+            } else {
+                target.add_links(-1);
+            }
+        }
+        self.node.remove(slot);
+        new_dir.insert(new_name, node.clone());
+        if is_dir && !Arc::ptr_eq(&self.node, &new_dir) {
+            if let TmpData::Dir { parent, .. } = &mut node.inner.exclusive_access().data {
+                *parent = Arc::downgrade(&new_dir);
+            }
+            self.node.add_links(-1);
+            new_dir.add_links(1);
+        }
+        node.inner.exclusive_access().ctime = get_epoch_sec();
+        true
+    }
+}
@@ -0,0 +1,219 @@
+//! The virtual filesystem switch, which puts the filesystems
+//! mounted at different paths under one namespace
+//!
+//! Paths handed to this module are absolute and free of "." and ".."
+//! components, as [`join_path`](super::join_path) makes them.
+
+use super::devfs::DevFs;
+use super::easyfs::EasyFs;
+use super::procfs::ProcFs;
+use super::tmpfs::TmpFs;
+use super::{File, Stat, StatMode};
+use crate::sync::UPSafeCell;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use lazy_static::*;
+
+/// An entry of a directory
+pub struct Dirent {
+    /// inode number of the entry
+    pub ino: u64,
+    /// name of the entry
+    pub name: String,
+    /// type of the entry, one of the types in `StatMode`
+    pub mode: StatMode,
+}
+
+/// A filesystem which can be mounted
+pub trait FileSystem: Send + Sync {
+    /// The root directory
+    fn root(&self) -> Arc<dyn VfsInode>;
+    /// Write back whatever is cached
+    fn sync(&self) {}
+}
+
+/// An inode of a filesystem,
+/// whose operations the filesystem does not support fail by default
+pub trait VfsInode: Send + Sync {
+    /// This as `Any`, for filesystems to get their own inodes back
+    fn as_any(&self) -> &dyn Any;
+    fn stat(&self) -> Stat;
+    fn is_dir(&self) -> bool {
+        self.stat().mode.contains(StatMode::DIR)
+    }
+    fn size(&self) -> usize;
+    /// Find an inode under this directory by name
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>>;
+    /// Read the first dirent at or after `offset` into this directory
+    /// returns the dirent and the offset to read the next one from
+    fn read_dir(&self, offset: usize) -> Option<(Dirent, usize)>;
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> usize {
+        0
+    }
+    fn truncate(&self, _size: usize) -> bool {
+        false
+    }
+    fn create(&self, _name: &str) -> Option<Arc<dyn VfsInode>> {
+        None
+    }
+    fn mkdir(&self, _name: &str) -> Option<Arc<dyn VfsInode>> {
+        None
+    }
+    /// Link `inode`, which is of the same filesystem, as `name` under this directory
+    fn link(&self, _name: &str, _inode: &dyn VfsInode) -> bool {
+        false
+    }
+    fn unlink(&self, _name: &str) -> bool {
+        false
+    }
+    fn rmdir(&self, _name: &str) -> bool {
+        false
+    }
+    /// Move `old_name` under this directory to `new_name` under `new_dir`,
+    /// which is of the same filesystem
+    fn rename(&self, _old_name: &str, _new_dir: &dyn VfsInode, _new_name: &str) -> bool {
+        false
+    }
+    fn fsync(&self) {}
+    /// Called when a file is opened on this, which keeps the inode and its data
+    /// around after its last name is gone until `close_handle` is called as often
+    fn open_handle(&self) {}
+    /// Called when a file opened on this is gone
+    fn close_handle(&self) {}
+    /// The file to open this as, for inodes such as devices
+    /// which do more than read and write their data
+    fn open(&self) -> Option<Arc<dyn File + Send + Sync>> {
+        None
+    }
+}
+
+/// A filesystem mounted at a path
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+lazy_static! {
+    /// The mounted filesystems, easy-fs at "/", devfs at "/dev",
+    /// tmpfs at "/tmp" and procfs at "/proc" from the start
+    static ref MOUNTS: UPSafeCell<Vec<Mount>> = unsafe {
+        UPSafeCell::new(vec![
+            Mount { path: String::from("/"), fs: Arc::new(EasyFs) },
+            Mount { path: String::from("/dev"), fs: Arc::new(DevFs) },
+            Mount { path: String::from("/tmp"), fs: Arc::new(TmpFs::new()) },
+            Mount { path: String::from("/proc"), fs: Arc::new(ProcFs) },
+        ])
+    };
+}
+
+/// The rest of `path` within the mount point `mount`,
+/// or None if `path` is not at or below `mount`
+fn strip_mount<'a>(path: &'a str, mount: &str) -> Option<&'a str> {
+    if mount == "/" {
+        return Some(path);
+    }
+    let rest = path.strip_prefix(mount)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Find the mount deepest along `path`
+/// returns the index of the mount, its filesystem and the rest of `path` within it
+fn locate(path: &str) -> (usize, Arc<dyn FileSystem>, &str) {
+    let mounts = MOUNTS.exclusive_access();
+    let (index, rest) = mounts
+        .iter()
+        .enumerate()
+        .filter_map(|(index, mount)| Some((index, strip_mount(path, mount.path.as_str())?)))
+        .min_by_key(|&(_, rest)| rest.len())
+        .unwrap();
+    (index, mounts[index].fs.clone(), rest)
+}
+
+/// Find the inode at `path`, crossing into the filesystems mounted along it
+pub fn lookup(path: &str) -> Option<Arc<dyn VfsInode>> {
+    let (_, fs, rest) = locate(path);
+    let mut inode = fs.root();
+    for name in rest.split('/').filter(|name| !name.is_empty()) {
+        inode = inode.find(name)?;
+    }
+    Some(inode)
+}
+
+/// Whether `path` and `other` are in the same mounted filesystem
+pub fn same_mount(path: &str, other: &str) -> bool {
+    locate(path).0 == locate(other).0
+}
+
+/// Whether a filesystem is mounted at `path` or below it,
+/// which keeps `path` from being removed or moved
+pub fn covers_mount(path: &str) -> bool {
+    MOUNTS
+        .exclusive_access()
+        .iter()
+        .any(|mount| strip_mount(mount.path.as_str(), path).is_some())
+}
+
+/// Create a filesystem of the type named `fstype` to be mounted
+pub fn new_filesystem(fstype: &str) -> Option<Arc<dyn FileSystem>> {
+    let fs: Arc<dyn FileSystem> = match fstype {
+        // easy-fs is on the only block device, so it is the same filesystem again
+        "easy-fs" => Arc::new(EasyFs),
+        "devfs" => Arc::new(DevFs),
+        "tmpfs" => Arc::new(TmpFs::new()),
+        "proc" => Arc::new(ProcFs),
+        _ => return None,
+    };
+    Some(fs)
+}
+
+/// Mount `fs` at the directory `path`, unless something is mounted there already
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> bool {
+    if !lookup(path).map_or(false, |inode| inode.is_dir()) {
+        return false;
+    }
+    let mut mounts = MOUNTS.exclusive_access();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return false;
+    }
+    mounts.push(Mount { path: String::from(path), fs });
+    true
+}
+
+/// Unmount the filesystem at `path`, unless "/" or another filesystem is mounted below it
+pub fn umount(path: &str) -> bool {
+    let mut mounts = MOUNTS.exclusive_access();
+    let index = match mounts.iter().position(|mount| mount.path == path) {
+        Some(index) if path != "/" => index,
+        _ => return false,
+    };
+    let nested = mounts
+        .iter()
+        .any(|mount| mount.path != path && strip_mount(mount.path.as_str(), path).is_some());
+    if nested {
+        return false;
+    }
+    let fs = mounts.remove(index).fs;
+    drop(mounts);
+    fs.sync();
+    true
+}
+
+/// Write all the dirty data of the mounted filesystems back
+pub fn sync_all() {
+    let filesystems: Vec<Arc<dyn FileSystem>> = MOUNTS
+        .exclusive_access()
+        .iter()
+        .map(|mount| mount.fs.clone())
+        .collect();
+    for fs in filesystems {
+        fs.sync();
+    }
+}
@@ -37,6 +37,13 @@ impl Drop for FrameTracker {
     }
 }
 
+/// How many physical frames there are and how many are free
+#[derive(Debug, Clone, Copy)]
+pub struct FrameUsage {
+    pub total: usize,
+    pub free: usize,
+}
+
 trait FrameAllocator {
     fn new() -> Self;
     fn alloc(&mut self) -> Option<PhysPageNum>;
@@ -45,6 +52,7 @@ trait FrameAllocator {
 
 /// an implementation for frame allocator
 pub struct StackFrameAllocator {
+    start: usize,
     current: usize,
     end: usize,
     recycled: Vec<usize>,
@@ -52,14 +60,23 @@ pub struct StackFrameAllocator {
 
 impl StackFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.start = l.0;
         self.current = l.0;
         self.end = r.0;
         info!("last {} Physical Frames.", self.end - self.current);
     }
+    /// The numbers of frames managed and of those free
+    pub fn usage(&self) -> FrameUsage {
+        FrameUsage {
+            total: self.end - self.start,
+            free: self.end - self.current + self.recycled.len(),
+        }
+    }
 }
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
         Self {
+            start: 0,
             current: 0,
             end: 0,
             recycled: Vec::new(),
@@ -112,6 +129,11 @@ pub fn frame_alloc() -> Option<FrameTracker> {
         .map(FrameTracker::new)
 }
 
+/// get the usage of the physical frames
+pub fn frame_usage() -> FrameUsage {
+    FRAME_ALLOCATOR.exclusive_access().usage()
+}
+
 /// deallocate a frame
 pub fn frame_dealloc(ppn: PhysPageNum) {
     FRAME_ALLOCATOR.exclusive_access().dealloc(ppn);
@@ -245,6 +245,17 @@ impl MemorySet {
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
     }
+    /// The start, the end and the permission of each area
+    pub fn area_list(&self) -> Vec<(VirtAddr, VirtAddr, MapPermission)> {
+        self.areas
+            .iter()
+            .map(|area| {
+                let start: VirtAddr = area.vpn_range.get_start().into();
+                let end: VirtAddr = area.vpn_range.get_end().into();
+                (start, end, area.map_perm)
+            })
+            .collect()
+    }
     pub fn recycle_data_pages(&mut self) {
         //*self = Self::new_bare();
         self.areas.clear();
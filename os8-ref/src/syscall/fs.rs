@@ -1,19 +1,83 @@
 //! File and filesystem-related syscalls
 
 use crate::fs::make_pipe;
-use crate::fs::open_file;
+use crate::fs::open_file_at;
 use crate::fs::OpenFlags;
 use crate::fs::Stat;
+use crate::fs::{find_dir, join_path, link_at, mkdir_at, rename_at, unlink_at};
+use crate::fs::{mount, new_filesystem, umount};
+use crate::fs::sync_all;
 use crate::mm::translated_byte_buffer;
 use crate::mm::translated_refmut;
 use crate::mm::translated_str;
 use crate::mm::UserBuffer;
 use crate::task::current_process;
 use crate::task::current_user_token;
+use alloc::string::String;
 use alloc::sync::Arc;
 
+/// Special value of `dirfd` referring to the current working directory
+const AT_FDCWD: isize = -100;
+/// Flag of `unlinkat` to remove a directory instead of a file
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// Get the absolute path of the directory
+/// which a relative path given along with `dirfd` starts from
+fn dirfd_path(dirfd: isize) -> Option<String> {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if dirfd == AT_FDCWD {
+        Some(inner.cwd.clone())
+    } else if dirfd >= 0 {
+        let file = inner.fd_table.get(dirfd as usize)?.as_ref()?.clone();
+        // release current process PCB, procfs borrows every PCB to look into it
+        drop(inner);
+        file.inode()
+            .filter(|inode| inode.is_dir())
+            .and_then(|_| file.path())
+    } else {
+        None
+    }
+}
+
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    match &inner.fd_table[fd] {
+        Some(file) if file.writable() => {
+            let file = file.clone();
+            // release current process TCB manually to avoid multi-borrow
+            drop(inner);
+            file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        }
+        _ => -1,
+    }
+}
+
+pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    match &inner.fd_table[fd] {
+        Some(file) if file.readable() => {
+            let file = file.clone();
+            // release current process TCB manually to avoid multi-borrow
+            drop(inner);
+            file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        }
+        _ => -1,
+    }
+}
+
+/// Move the offset of `fd` as `whence` says and return the new offset
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
     let process = current_process();
     let inner = process.inner_exclusive_access();
     if fd >= inner.fd_table.len() {
@@ -21,15 +85,56 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     }
     if let Some(file) = &inner.fd_table[fd] {
         let file = file.clone();
-        // release current process TCB manually to avoid multi-borrow
         drop(inner);
-        file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        file.seek(offset, whence).map_or(-1, |offset| offset as isize)
     } else {
         -1
     }
 }
 
-pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
+/// Read from `fd` at `offset` without moving its offset
+pub fn sys_pread64(fd: usize, buf: *mut u8, len: usize, offset: usize) -> isize {
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    match &inner.fd_table[fd] {
+        Some(file) if file.readable() => {
+            let file = file.clone();
+            // release current process TCB manually to avoid multi-borrow
+            drop(inner);
+            file.read_at(offset, UserBuffer::new(translated_byte_buffer(token, buf, len)))
+                .map_or(-1, |size| size as isize)
+        }
+        _ => -1,
+    }
+}
+
+/// Write to `fd` at `offset` without moving its offset
+pub fn sys_pwrite64(fd: usize, buf: *const u8, len: usize, offset: usize) -> isize {
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    match &inner.fd_table[fd] {
+        Some(file) if file.writable() => {
+            let file = file.clone();
+            // release current process TCB manually to avoid multi-borrow
+            drop(inner);
+            file.write_at(offset, UserBuffer::new(translated_byte_buffer(token, buf, len)))
+                .map_or(-1, |size| size as isize)
+        }
+        _ => -1,
+    }
+}
+
+/// Read the next entries of the directory `fd` into `buf` as `linux_dirent64` records
+/// and return the bytes filled, 0 at the end of the directory
+pub fn sys_getdents64(fd: usize, buf: *mut u8, len: usize) -> isize {
     let token = current_user_token();
     let process = current_process();
     let inner = process.inner_exclusive_access();
@@ -40,17 +145,22 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
         let file = file.clone();
         // release current process TCB manually to avoid multi-borrow
         drop(inner);
-        file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        file.read_dir(UserBuffer::new(translated_byte_buffer(token, buf, len)))
+            .map_or(-1, |size| size as isize)
     } else {
         -1
     }
 }
 
-pub fn sys_open(path: *const u8, flags: u32) -> isize {
+pub fn sys_open(dirfd: isize, path: *const u8, flags: u32) -> isize {
     let process = current_process();
     let token = current_user_token();
     let path = translated_str(token, path);
-    if let Some(inode) = open_file(path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
+    let dir = match dirfd_path(dirfd) {
+        Some(dir) => dir,
+        None => return -1,
+    };
+    if let Some(inode) = open_file_at(dir.as_str(), path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
         let mut inner = process.inner_exclusive_access();
         let fd = inner.alloc_fd();
         inner.fd_table[fd] = Some(inode);
@@ -101,14 +211,211 @@ pub fn sys_dup(fd: usize) -> isize {
     new_fd as isize
 }
 
-pub fn sys_fstat(_fd: usize, _st: *mut Stat) -> isize {
-    -1
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    let file = match &inner.fd_table[fd] {
+        Some(file) => file.clone(),
+        None => return -1,
+    };
+    drop(inner);
+    match file.stat() {
+        Some(stat) => {
+            *translated_refmut(token, st) = stat;
+            0
+        }
+        None => -1,
+    }
+}
+
+/// Carry out a device-specific `request` on `fd`
+pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> isize {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        drop(inner);
+        file.ioctl(request, arg).unwrap_or(-1)
+    } else {
+        -1
+    }
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    let inode = match inner.fd_table[fd].as_ref().and_then(|file| file.inode()) {
+        Some(inode) => inode,
+        None => return -1,
+    };
+    drop(inner);
+    inode.fsync();
+    0
+}
+
+pub fn sys_ftruncate(fd: usize, length: usize) -> isize {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    let inode = match inner.fd_table[fd]
+        .as_ref()
+        .filter(|file| file.writable())
+        .and_then(|file| file.inode())
+    {
+        Some(inode) => inode,
+        None => return -1,
+    };
+    drop(inner);
+    if inode.truncate(length) {
+        0
+    } else {
+        -1
+    }
+}
+
+pub fn sys_sync() -> isize {
+    sync_all();
+    0
+}
+
+pub fn sys_linkat(
+    old_dirfd: isize,
+    old_path: *const u8,
+    new_dirfd: isize,
+    new_path: *const u8,
+) -> isize {
+    let token = current_user_token();
+    let old_path = translated_str(token, old_path);
+    let new_path = translated_str(token, new_path);
+    let (old_dir, new_dir) = match (dirfd_path(old_dirfd), dirfd_path(new_dirfd)) {
+        (Some(old_dir), Some(new_dir)) => (old_dir, new_dir),
+        _ => return -1,
+    };
+    if link_at(old_dir.as_str(), old_path.as_str(), new_dir.as_str(), new_path.as_str()) {
+        0
+    } else {
+        -1
+    }
+}
+
+pub fn sys_renameat(
+    old_dirfd: isize,
+    old_path: *const u8,
+    new_dirfd: isize,
+    new_path: *const u8,
+) -> isize {
+    let token = current_user_token();
+    let old_path = translated_str(token, old_path);
+    let new_path = translated_str(token, new_path);
+    let (old_dir, new_dir) = match (dirfd_path(old_dirfd), dirfd_path(new_dirfd)) {
+        (Some(old_dir), Some(new_dir)) => (old_dir, new_dir),
+        _ => return -1,
+    };
+    if rename_at(old_dir.as_str(), old_path.as_str(), new_dir.as_str(), new_path.as_str()) {
+        0
+    } else {
+        -1
+    }
+}
+
+pub fn sys_unlinkat(dirfd: isize, path: *const u8, flags: u32) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let dir = match dirfd_path(dirfd) {
+        Some(dir) => dir,
+        None => return -1,
+    };
+    if unlink_at(dir.as_str(), path.as_str(), flags & AT_REMOVEDIR != 0) {
+        0
+    } else {
+        -1
+    }
+}
+
+pub fn sys_mkdirat(dirfd: isize, path: *const u8, _mode: u32) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let dir = match dirfd_path(dirfd) {
+        Some(dir) => dir,
+        None => return -1,
+    };
+    if mkdir_at(dir.as_str(), path.as_str()) {
+        0
+    } else {
+        -1
+    }
+}
+
+pub fn sys_chdir(path: *const u8) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let process = current_process();
+    let cwd = process.inner_exclusive_access().cwd.clone();
+    let cwd = join_path(cwd.as_str(), path.as_str());
+    // look up without the PCB borrowed, procfs borrows every PCB to look into it
+    if find_dir(cwd.as_str()).is_none() {
+        return -1;
+    }
+    process.inner_exclusive_access().cwd = cwd;
+    0
 }
 
-pub fn sys_linkat(_old_name: *const u8, _new_name: *const u8) -> isize {
-    -1
+/// Mount a new filesystem of the type `fstype` at the directory `target`,
+/// `source` and `flags` are ignored since no filesystem needs them
+pub fn sys_mount(_source: *const u8, target: *const u8, fstype: *const u8, _flags: usize) -> isize {
+    let token = current_user_token();
+    let target = translated_str(token, target);
+    let fstype = translated_str(token, fstype);
+    let cwd = current_process().inner_exclusive_access().cwd.clone();
+    let fs = match new_filesystem(fstype.as_str()) {
+        Some(fs) => fs,
+        None => return -1,
+    };
+    if mount(join_path(cwd.as_str(), target.as_str()).as_str(), fs) {
+        0
+    } else {
+        -1
+    }
 }
 
-pub fn sys_unlinkat(_name: *const u8) -> isize {
-    -1
+/// Unmount the filesystem mounted at `target`
+pub fn sys_umount2(target: *const u8, _flags: usize) -> isize {
+    let token = current_user_token();
+    let target = translated_str(token, target);
+    let cwd = current_process().inner_exclusive_access().cwd.clone();
+    if umount(join_path(cwd.as_str(), target.as_str()).as_str()) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Copy the current working directory into `buf` as a NUL-terminated string
+/// and return its length including the NUL, or -1 if `buf` is too small
+pub fn sys_getcwd(buf: *mut u8, len: usize) -> isize {
+    let token = current_user_token();
+    let process = current_process();
+    let mut cwd = process.inner_exclusive_access().cwd.clone().into_bytes();
+    cwd.push(0);
+    if cwd.len() > len {
+        return -1;
+    }
+    let mut copied = 0usize;
+    for slice in translated_byte_buffer(token, buf, cwd.len()) {
+        slice.copy_from_slice(&cwd[copied..copied + slice.len()]);
+        copied += slice.len();
+    }
+    cwd.len() as isize
 }
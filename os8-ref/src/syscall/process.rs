@@ -45,7 +45,9 @@ pub fn sys_getpid() -> isize {
 /// Syscall Fork which returns 0 for child process and child_pid for parent process
 pub fn sys_fork() -> isize {
     let current_process = current_process();
-    let new_process = current_process.fork();
+    // the child is scheduled as the thread calling fork is
+    let sched = current_task().unwrap().inner_exclusive_access().sched.inherit();
+    let new_process = current_process.fork(sched);
     let new_pid = new_process.getpid();
     // modify trap context of new_task, because it returns immediately after switching
     let new_process_inner = new_process.inner_exclusive_access();
@@ -139,8 +141,13 @@ pub fn sys_task_info(_ti: *mut TaskInfo) -> isize {
     -1
 }
 
-pub fn sys_set_priority(_prio: isize) -> isize {
-    -1
+/// Set the priority of the current thread, which must be at least 2
+pub fn sys_set_priority(prio: isize) -> isize {
+    if prio < 2 {
+        return -1;
+    }
+    current_task().unwrap().inner_exclusive_access().sched.priority = prio as usize;
+    prio
 }
 
 pub fn sys_mmap(_start: usize, _len: usize, _port: usize) -> isize {
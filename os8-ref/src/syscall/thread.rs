@@ -18,7 +18,10 @@ pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
             .ustack_base,
         true,
     ));
-    let new_task_inner = new_task.inner_exclusive_access();
+    // the new thread is scheduled as the one creating it is
+    let sched = task.inner_exclusive_access().sched.inherit();
+    let mut new_task_inner = new_task.inner_exclusive_access();
+    new_task_inner.sched = sched;
     let new_task_res = new_task_inner.res.as_ref().unwrap();
     let new_task_tid = new_task_res.tid;
     let new_task_trap_cx = new_task_inner.get_trap_cx();
@@ -30,6 +33,8 @@ pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
         trap_handler as usize,
     );
     (*new_task_trap_cx).x[10] = arg;
+    // release the new TCB, which the scheduler looks into when it is added
+    drop(new_task_inner);
 
     let mut process_inner = process.inner_exclusive_access();
     // add new thread to current process
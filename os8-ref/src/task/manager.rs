@@ -4,37 +4,20 @@
 //! Other CPU process monitoring functions are in Processor.
 
 
+use super::scheduler::{new_scheduler, Scheduler};
 use super::TaskControlBlock;
 use crate::sync::UPSafeCell;
-use alloc::collections::VecDeque;
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use lazy_static::*;
 
-pub struct TaskManager {
-    ready_queue: VecDeque<Arc<TaskControlBlock>>,
-}
-
-/// A simple FIFO scheduler.
-impl TaskManager {
-    pub fn new() -> Self {
-        Self {
-            ready_queue: VecDeque::new(),
-        }
-    }
-    /// Add process back to ready queue
-    pub fn add(&mut self, task: Arc<TaskControlBlock>) {
-        self.ready_queue.push_back(task);
-    }
-    /// Take a process out of the ready queue
-    pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
-        self.ready_queue.pop_front()
-    }
-}
+/// The ready tasks, in the scheduler of the policy built in
+pub type TaskManager = Box<dyn Scheduler>;
 
 lazy_static! {
     /// TASK_MANAGER instance through lazy_static!
     pub static ref TASK_MANAGER: UPSafeCell<TaskManager> =
-        unsafe { UPSafeCell::new(TaskManager::new()) };
+        unsafe { UPSafeCell::new(new_scheduler()) };
 }
 
 pub fn add_task(task: Arc<TaskControlBlock>) {
@@ -44,3 +27,8 @@ pub fn add_task(task: Arc<TaskControlBlock>) {
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
     TASK_MANAGER.exclusive_access().fetch()
 }
+
+/// Account `cycles` of running to `task`, which has just left the processor
+pub fn charge_task(task: &TaskControlBlock, cycles: usize) {
+    TASK_MANAGER.exclusive_access().charge(task, cycles);
+}
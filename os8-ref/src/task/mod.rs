@@ -15,6 +15,7 @@ pub mod kthread;
 mod manager;
 mod process;
 mod processor;
+mod scheduler;
 pub mod stackless_coroutine;
 mod switch;
 #[allow(clippy::module_inception)]
@@ -25,14 +26,14 @@ use crate::{
     fs::{open_file, OpenFlags},
     task::id::TaskUserRes,
 };
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{sync::Arc, vec, vec::Vec};
 pub use context::TaskContext;
 pub use id::{kstack_alloc, pid_alloc, KernelStack, PidHandle};
 pub use kthread::kernel_stackful_coroutine_test;
 use lazy_static::*;
 pub use manager::add_task;
-use manager::fetch_task;
-use process::ProcessControlBlock;
+use manager::{charge_task, fetch_task};
+pub use process::ProcessControlBlock;
 pub use processor::{
     current_process, current_task, current_trap_cx, current_trap_cx_user_va, current_user_token,
     run_tasks, schedule, take_current_task,
@@ -151,6 +152,23 @@ lazy_static! {
     };
 }
 
+/// The processes not yet waited for, found by walking down from `INITPROC`
+pub fn process_list() -> Vec<Arc<ProcessControlBlock>> {
+    let mut list = Vec::new();
+    let mut stack = vec![INITPROC.clone()];
+    while let Some(process) = stack.pop() {
+        stack.extend(process.inner_exclusive_access().children.iter().cloned());
+        list.push(process);
+    }
+    list.sort_by_key(|process| process.getpid());
+    list
+}
+
+/// Find a process not yet waited for by its pid
+pub fn find_process(pid: usize) -> Option<Arc<ProcessControlBlock>> {
+    process_list().into_iter().find(|process| process.getpid() == pid)
+}
+
 pub fn add_initproc() {
     // INITPROC must be referenced at least once so that it can be initialized
     // through lazy_static
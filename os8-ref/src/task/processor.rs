@@ -6,9 +6,10 @@
 
 use super::__switch;
 use super::process::ProcessControlBlock;
-use super::{fetch_task, TaskStatus};
+use super::{charge_task, fetch_task, TaskStatus};
 use super::{TaskContext, TaskControlBlock};
 use crate::sync::UPSafeCell;
+use crate::timer::get_time;
 use crate::trap::TrapContext;
 use alloc::sync::Arc;
 use lazy_static::*;
@@ -19,6 +20,8 @@ pub struct Processor {
     current: Option<Arc<TaskControlBlock>>,
     /// The basic control flow of each core, helping to select and switch process
     idle_task_cx: TaskContext,
+    /// When the current task was switched to
+    dispatched_at: usize,
 }
 
 impl Processor {
@@ -26,13 +29,17 @@ impl Processor {
         Self {
             current: None,
             idle_task_cx: TaskContext::zero_init(),
+            dispatched_at: 0,
         }
     }
     fn get_idle_task_cx_ptr(&mut self) -> *mut TaskContext {
         &mut self.idle_task_cx as *mut _
     }
     pub fn take_current(&mut self) -> Option<Arc<TaskControlBlock>> {
-        self.current.take()
+        let task = self.current.take()?;
+        // the task is leaving the processor, so it has run up to now
+        charge_task(&task, get_time() - self.dispatched_at);
+        Some(task)
     }
     pub fn current(&self) -> Option<Arc<TaskControlBlock>> {
         self.current.as_ref().map(|task| Arc::clone(task))
@@ -61,6 +68,7 @@ pub fn run_tasks() {
             drop(task_inner);
             // release coming task TCB manually
             processor.current = Some(task);
+            processor.dispatched_at = get_time();
             // release processor manually
             drop(processor);
             unsafe {
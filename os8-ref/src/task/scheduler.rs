@@ -0,0 +1,295 @@
+//! Scheduling policies behind [`TaskManager`](super::manager)
+//!
+//! The policy is picked at build time by the cargo features
+//! `sched-stride`, `sched-mlfq` and `sched-cfs`, FIFO round-robin without any.
+
+use super::TaskControlBlock;
+use crate::config::CLOCK_FREQ;
+use crate::timer::{get_time, TICKS_PER_SEC};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BinaryHeap, VecDeque};
+use alloc::sync::Arc;
+use core::cmp::Ordering;
+
+#[cfg(any(
+    all(feature = "sched-stride", feature = "sched-mlfq"),
+    all(feature = "sched-stride", feature = "sched-cfs"),
+    all(feature = "sched-mlfq", feature = "sched-cfs"),
+))]
+compile_error!("at most one of the features sched-stride, sched-mlfq and sched-cfs can be enabled");
+
+/// The priority of a task until it sets its own
+pub const DEFAULT_PRIORITY: usize = 16;
+
+/// The state of a task that the scheduling policies keep
+pub struct SchedEntity {
+    /// set by `sys_set_priority`, at least 2
+    pub priority: usize,
+    /// the pass of stride scheduling
+    pub pass: usize,
+    /// the queue of the multi-level feedback queue the task is in
+    pub level: usize,
+    /// the time the task has run at `level`, in `get_time` cycles
+    pub level_used: usize,
+    /// the virtual runtime of the CFS-like policy
+    pub vruntime: usize,
+}
+
+impl SchedEntity {
+    pub fn new() -> Self {
+        Self {
+            priority: DEFAULT_PRIORITY,
+            pass: 0,
+            level: 0,
+            level_used: 0,
+            vruntime: 0,
+        }
+    }
+    /// The state a task forked or created by the task of this starts in,
+    /// of the same priority and as far along as this task
+    pub fn inherit(&self) -> Self {
+        Self {
+            priority: self.priority,
+            pass: self.pass,
+            level: 0,
+            level_used: 0,
+            vruntime: self.vruntime,
+        }
+    }
+}
+
+/// A policy choosing which of the ready tasks runs next
+pub trait Scheduler: Send + Sync {
+    /// Put a task which is ready to run in
+    fn add(&mut self, task: Arc<TaskControlBlock>);
+    /// Take the task to run next out
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>>;
+    /// Account `cycles` of running to `task`, which has just left the processor
+    fn charge(&mut self, _task: &TaskControlBlock, _cycles: usize) {}
+}
+
+/// The scheduler the cargo features ask for
+pub fn new_scheduler() -> Box<dyn Scheduler> {
+    if cfg!(feature = "sched-stride") {
+        Box::new(StrideScheduler::new())
+    } else if cfg!(feature = "sched-mlfq") {
+        Box::new(MlfqScheduler::new())
+    } else if cfg!(feature = "sched-cfs") {
+        Box::new(CfsScheduler::new())
+    } else {
+        Box::new(FifoScheduler::new())
+    }
+}
+
+/// Round-robin in the order the tasks become ready
+pub struct FifoScheduler {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl FifoScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Scheduler for FifoScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queue.pop_front()
+    }
+}
+
+/// The pass a task of priority 1 would make each time it runs
+///
+/// As the priorities are at least 2, the passes of the ready tasks
+/// stay within `BIG_STRIDE / 2` of each other, so they still compare right
+/// once they wrap around, as [`pass_cmp`] compares them.
+pub const BIG_STRIDE: usize = 1 << 20;
+
+/// Compare two passes which may have wrapped around since they were close
+fn pass_cmp(a: usize, b: usize) -> Ordering {
+    (a.wrapping_sub(b) as isize).cmp(&0)
+}
+
+/// A ready task in the heap of stride scheduling
+struct StrideEntry {
+    pass: usize,
+    /// the order it came in, so that the tasks of equal passes take turns
+    arrival: usize,
+    task: Arc<TaskControlBlock>,
+}
+
+impl PartialEq for StrideEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for StrideEntry {}
+impl PartialOrd for StrideEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for StrideEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed, so that the max-heap pops the least pass first
+        pass_cmp(other.pass, self.pass).then(pass_cmp(other.arrival, self.arrival))
+    }
+}
+
+/// Stride scheduling: the task of the least pass runs,
+/// then its pass goes up by `BIG_STRIDE / priority`
+pub struct StrideScheduler {
+    ready_queue: BinaryHeap<StrideEntry>,
+    /// the number of tasks ever added
+    arrivals: usize,
+    /// the pass of the task fetched last, the least of the ready ones
+    min_pass: usize,
+}
+
+impl StrideScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: BinaryHeap::new(),
+            arrivals: 0,
+            min_pass: 0,
+        }
+    }
+}
+
+impl Scheduler for StrideScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let mut task_inner = task.inner_exclusive_access();
+        // a task back from blocking catches up with the others
+        // instead of running until its pass does
+        if pass_cmp(task_inner.sched.pass, self.min_pass) == Ordering::Less {
+            task_inner.sched.pass = self.min_pass;
+        }
+        let pass = task_inner.sched.pass;
+        drop(task_inner);
+        self.ready_queue.push(StrideEntry { pass, arrival: self.arrivals, task });
+        self.arrivals = self.arrivals.wrapping_add(1);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let StrideEntry { pass, task, .. } = self.ready_queue.pop()?;
+        self.min_pass = pass;
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.sched.pass = pass.wrapping_add(BIG_STRIDE / task_inner.sched.priority);
+        drop(task_inner);
+        Some(task)
+    }
+}
+
+/// The number of queues of the multi-level feedback queue
+const MLFQ_LEVELS: usize = 3;
+/// How often all the tasks go back to the top queue, in `get_time` cycles
+const MLFQ_BOOST_PERIOD: usize = CLOCK_FREQ;
+
+/// How long a task may run at `level` before it moves a queue down,
+/// doubling from a single time slice at the top
+fn mlfq_allotment(level: usize) -> usize {
+    (CLOCK_FREQ / TICKS_PER_SEC) << level
+}
+
+/// A multi-level feedback queue: the tasks of the highest non-empty queue run
+/// round-robin, a task that uses up its allotment moves a queue down,
+/// and every task goes back to the top now and then so that none starves
+pub struct MlfqScheduler {
+    queues: [VecDeque<Arc<TaskControlBlock>>; MLFQ_LEVELS],
+    last_boost: usize,
+}
+
+impl MlfqScheduler {
+    pub fn new() -> Self {
+        Self {
+            queues: Default::default(),
+            last_boost: 0,
+        }
+    }
+    /// Move all the ready tasks to the top queue
+    fn boost(&mut self) {
+        for level in 1..MLFQ_LEVELS {
+            while let Some(task) = self.queues[level].pop_front() {
+                self.queues[0].push_back(task);
+            }
+        }
+        for task in self.queues[0].iter() {
+            let mut task_inner = task.inner_exclusive_access();
+            task_inner.sched.level = 0;
+            task_inner.sched.level_used = 0;
+        }
+    }
+}
+
+impl Scheduler for MlfqScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let level = task.inner_exclusive_access().sched.level;
+        self.queues[level].push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let now = get_time();
+        if now - self.last_boost >= MLFQ_BOOST_PERIOD {
+            self.boost();
+            self.last_boost = now;
+        }
+        self.queues.iter_mut().find_map(|queue| queue.pop_front())
+    }
+    fn charge(&mut self, task: &TaskControlBlock, cycles: usize) {
+        let mut task_inner = task.inner_exclusive_access();
+        let sched = &mut task_inner.sched;
+        sched.level_used += cycles;
+        if sched.level + 1 < MLFQ_LEVELS && sched.level_used >= mlfq_allotment(sched.level) {
+            sched.level += 1;
+            sched.level_used = 0;
+        }
+    }
+}
+
+/// A policy like Linux CFS: the task of the least virtual runtime runs,
+/// the virtual runtime growing slower the higher the priority
+pub struct CfsScheduler {
+    /// the ready tasks by virtual runtime, then by the order they came in
+    ready_queue: BTreeMap<(usize, usize), Arc<TaskControlBlock>>,
+    /// the number of tasks ever added, to keep the keys apart
+    arrivals: usize,
+    /// the virtual runtime the tasks have run up to,
+    /// where the tasks newly ready start from
+    min_vruntime: usize,
+}
+
+impl CfsScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: BTreeMap::new(),
+            arrivals: 0,
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl Scheduler for CfsScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let mut task_inner = task.inner_exclusive_access();
+        // a task back from sleeping does not get to make up for all that time
+        let vruntime = task_inner.sched.vruntime.max(self.min_vruntime);
+        task_inner.sched.vruntime = vruntime;
+        drop(task_inner);
+        self.ready_queue.insert((vruntime, self.arrivals), task);
+        self.arrivals += 1;
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let key = *self.ready_queue.keys().next()?;
+        self.min_vruntime = self.min_vruntime.max(key.0);
+        self.ready_queue.remove(&key)
+    }
+    fn charge(&mut self, task: &TaskControlBlock, cycles: usize) {
+        let mut task_inner = task.inner_exclusive_access();
+        let sched = &mut task_inner.sched;
+        sched.vruntime += cycles * DEFAULT_PRIORITY / sched.priority;
+    }
+}
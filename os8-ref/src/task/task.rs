@@ -1,6 +1,7 @@
 //! Types related to task management & Functions for completely changing TCB
 
 use super::id::TaskUserRes;
+use super::scheduler::SchedEntity;
 use super::{kstack_alloc, KernelStack, ProcessControlBlock, TaskContext};
 use crate::trap::TrapContext;
 use crate::{mm::PhysPageNum, sync::UPSafeCell};
@@ -34,6 +35,8 @@ pub struct TaskControlBlockInner {
     pub exit_code: Option<i32>,
     /// Tid and ustack will be deallocated when this goes None
     pub res: Option<TaskUserRes>,
+    /// What the scheduling policy keeps of this task
+    pub sched: SchedEntity,
 }
 
 /// Simple access to its internal fields
@@ -73,6 +76,7 @@ impl TaskControlBlock {
                     task_cx: TaskContext::goto_trap_return(kstack_top),
                     task_status: TaskStatus::Ready,
                     exit_code: None,
+                    sched: SchedEntity::new(),
                 })
             },
         }
@@ -124,6 +128,7 @@ impl TaskControlBlock {
                     task_cx: context,
                     task_status: TaskStatus::Ready,
                     exit_code: None,
+                    sched: SchedEntity::new(),
                 })
             },
         }
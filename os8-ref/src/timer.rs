@@ -1,6 +1,6 @@
 //! RISC-V timer-related functionality
 
-use crate::config::CLOCK_FREQ;
+use crate::config::{CLOCK_FREQ, RTC_BASE};
 use crate::sbi::set_timer;
 use crate::sync::UPSafeCell;
 use crate::task::{add_task, TaskControlBlock};
@@ -10,7 +10,8 @@ use core::cmp::Ordering;
 use lazy_static::*;
 use riscv::register::time;
 
-const TICKS_PER_SEC: usize = 100;
+/// timer interrupts per second, each ending a time slice
+pub const TICKS_PER_SEC: usize = 100;
 const MILLI_PER_SEC: usize = 1_000;
 const MICRO_PER_SEC: usize = 1_000_000;
 
@@ -29,6 +30,14 @@ pub fn get_time_ms() -> usize {
     time::read() / (CLOCK_FREQ / MILLI_PER_SEC)
 }
 
+/// get wall-clock time in seconds since the epoch, from the RTC
+pub fn get_epoch_sec() -> u64 {
+    // reading the low half latches the high half
+    let low = unsafe { (RTC_BASE as *const u32).read_volatile() } as u64;
+    let high = unsafe { ((RTC_BASE + 4) as *const u32).read_volatile() } as u64;
+    ((high << 32) | low) / 1_000_000_000
+}
+
 /// set the next timer interrupt
 pub fn set_next_trigger() {
     set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, open, read, write, OpenFlags};
+
+/// 测试设备文件 /dev/null、/dev/zero 与 /dev/random，输出 Test devfs OK! 就算正确。
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fd = open("/dev/null\0", OpenFlags::RDWR);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"discarded"), 9);
+    let mut buffer = [1u8; 32];
+    assert_eq!(read(fd, &mut buffer), 0);
+    close(fd);
+
+    let fd = open("/dev/zero\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(read(fd, &mut buffer), 32);
+    assert!(buffer.iter().all(|&byte| byte == 0));
+    assert_eq!(write(fd, b"read only"), -1);
+    close(fd);
+
+    let fd = open("/dev/null\0", OpenFlags::WRONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(read(fd, &mut buffer), -1);
+    close(fd);
+
+    let fd = open("/dev/random\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let mut other = [0u8; 32];
+    assert_eq!(read(fd, &mut buffer), 32);
+    assert_eq!(read(fd, &mut other), 32);
+    assert_ne!(buffer, other);
+    close(fd);
+
+    assert_eq!(open("/dev/nothing\0", OpenFlags::RDONLY), -1);
+    println!("Test devfs OK!");
+    0
+}
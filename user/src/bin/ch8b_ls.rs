@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::read_dir;
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let path = if argc > 1 { argv[1] } else { ".\0" };
+    let entries = match read_dir(path) {
+        Some(entries) => entries,
+        None => {
+            println!("ch8b_ls: cannot open {}", path.trim_end_matches('\0'));
+            return -1;
+        }
+    };
+    for entry in entries.filter(|entry| entry.name != "." && entry.name != "..") {
+        if entry.is_dir {
+            println!("{}/", entry.name);
+        } else {
+            println!("{}", entry.name);
+        }
+    }
+    0
+}
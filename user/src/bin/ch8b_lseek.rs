@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    close, lseek, open, pipe, pread, pwrite, read, unlink, write, OpenFlags, SEEK_CUR, SEEK_END,
+    SEEK_SET,
+};
+
+/// 测试 lseek、pread、pwrite 与 O_APPEND，输出 Test lseek OK! 就算正确。
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fd = open("lseek\0", OpenFlags::CREATE | OpenFlags::TRUNC | OpenFlags::RDWR);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"0123456789"), 10);
+
+    // lseek 三种 whence
+    assert_eq!(lseek(fd, 0, SEEK_CUR), 10);
+    assert_eq!(lseek(fd, 2, SEEK_SET), 2);
+    assert_eq!(lseek(fd, 3, SEEK_CUR), 5);
+    assert_eq!(lseek(fd, -4, SEEK_END), 6);
+    let mut buffer = [0u8; 10];
+    assert_eq!(read(fd, &mut buffer[..2]), 2);
+    assert_eq!(&buffer[..2], b"67");
+    assert_eq!(lseek(fd, -100, SEEK_CUR), -1);
+    assert_eq!(lseek(fd, 0, 3), -1);
+    assert_eq!(lseek(fd, 0, SEEK_CUR), 8);
+
+    // pread 与 pwrite 不移动偏移
+    assert_eq!(pwrite(fd, b"ab", 1), 2);
+    assert_eq!(pread(fd, &mut buffer, 0), 10);
+    assert_eq!(&buffer, b"0ab3456789");
+    assert_eq!(pread(fd, &mut buffer, 10), 0);
+    assert_eq!(lseek(fd, 0, SEEK_CUR), 8);
+
+    // 越过末尾写入留下空洞
+    assert_eq!(lseek(fd, 12, SEEK_SET), 12);
+    assert_eq!(write(fd, b"x"), 1);
+    assert_eq!(pread(fd, &mut buffer[..3], 10), 3);
+    assert_eq!(&buffer[..3], b"\0\0x");
+    close(fd);
+
+    // O_APPEND 总是写到末尾
+    let fd = open("lseek\0", OpenFlags::WRONLY | OpenFlags::APPEND);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(lseek(fd, 0, SEEK_SET), 0);
+    assert_eq!(write(fd, b"yz"), 2);
+    assert_eq!(lseek(fd, 0, SEEK_CUR), 15);
+    close(fd);
+    let fd = open("lseek\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(pread(fd, &mut buffer[..5], 10), 5);
+    assert_eq!(&buffer[..5], b"\0\0xyz");
+    close(fd);
+    assert_eq!(unlink("lseek\0"), 0);
+
+    // 管道与标准输入不能 seek
+    let mut pipe_fd = [0usize; 2];
+    assert_eq!(pipe(&mut pipe_fd), 0);
+    assert_eq!(lseek(pipe_fd[0], 0, SEEK_SET), -1);
+    assert_eq!(pread(pipe_fd[0], &mut buffer, 0), -1);
+    assert_eq!(pwrite(pipe_fd[1], b"a", 0), -1);
+    close(pipe_fd[0]);
+    close(pipe_fd[1]);
+    assert_eq!(lseek(0, 0, SEEK_CUR), -1);
+    assert_eq!(pread(0, &mut buffer, 0), -1);
+    println!("Test lseek OK!");
+    0
+}
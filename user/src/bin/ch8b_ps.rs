@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use user_lib::{close, open, read, read_dir, OpenFlags};
+
+/// Read the whole file at `path`, or None if it cannot be opened
+fn read_file(path: &str) -> Option<String> {
+    let fd = open(path, OpenFlags::RDONLY);
+    if fd < 0 {
+        return None;
+    }
+    let fd = fd as usize;
+    // decoded once read whole, as a character may be split between two reads
+    let mut bytes = Vec::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        let len = read(fd, &mut buffer);
+        if len <= 0 {
+            break;
+        }
+        bytes.extend_from_slice(&buffer[..len as usize]);
+    }
+    close(fd);
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The value of the `key: value` line of a status file
+fn field<'a>(status: &'a str, key: &str) -> &'a str {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(": "))
+        .unwrap_or("-")
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let entries = match read_dir("/proc\0") {
+        Some(entries) => entries,
+        None => {
+            println!("ch8b_ps: cannot open /proc");
+            return -1;
+        }
+    };
+    println!("PID\tPPID\tSTATE\tTHREADS\tCWD");
+    for entry in entries.filter(|entry| entry.name.parse::<usize>().is_ok()) {
+        // the process may have been waited for since it was listed
+        let status = match read_file(format!("/proc/{}/status\0", entry.name).as_str()) {
+            Some(status) => status,
+            None => continue,
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            field(&status, "Pid"),
+            field(&status, "PPid"),
+            field(&status, "State"),
+            field(&status, "Threads"),
+            field(&status, "Cwd")
+        );
+    }
+    0
+}
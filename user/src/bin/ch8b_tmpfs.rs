@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    close, ftruncate, mkdir, open, pwrite, read, read_dir, rmdir, unlink, write, OpenFlags,
+};
+
+/// 测试 /tmp 下的内存文件系统，输出 Test tmpfs OK! 就算正确。
+
+#[no_mangle]
+pub fn main() -> i32 {
+    assert_eq!(mkdir("/tmp/dir\0"), 0);
+    let test_str = "Hello, tmpfs!";
+    let fd = open("/tmp/dir/file\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, test_str.as_bytes()), test_str.len() as isize);
+    // 文件大小有上限
+    assert_eq!(pwrite(fd, b"far", 0x4000_0000), 0);
+    assert_eq!(ftruncate(fd, 0x4000_0000), -1);
+    close(fd);
+
+    let fd = open("/tmp/dir/file\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let mut buffer = [0u8; 100];
+    let read_len = read(fd, &mut buffer) as usize;
+    close(fd);
+    assert_eq!(test_str, core::str::from_utf8(&buffer[..read_len]).unwrap());
+
+    let mut names = read_dir("/tmp/dir\0").unwrap().map(|entry| entry.name);
+    assert_eq!(names.next().as_deref(), Some("."));
+    assert_eq!(names.next().as_deref(), Some(".."));
+    assert_eq!(names.next().as_deref(), Some("file"));
+    assert!(names.next().is_none());
+    drop(names);
+
+    assert_eq!(rmdir("/tmp/dir\0"), -1);
+    assert_eq!(unlink("/tmp/dir/file\0"), 0);
+    assert_eq!(rmdir("/tmp/dir\0"), 0);
+    assert_eq!(open("/tmp/dir/file\0", OpenFlags::RDONLY), -1);
+    println!("Test tmpfs OK!");
+    0
+}
@@ -16,7 +16,7 @@ const LINE_START: &str = ">> ";
 use alloc::string::String;
 use alloc::vec::Vec;
 use user_lib::console::getchar;
-use user_lib::{close, dup, exec, fork, open, pipe, waitpid, OpenFlags};
+use user_lib::{chdir, close, dup, exec, fork, open, pipe, waitpid, OpenFlags};
 
 #[derive(Debug)]
 struct ProcessArguments {
@@ -85,6 +85,18 @@ pub fn main() -> i32 {
             LF | CR => {
                 println!("");
                 if !line.is_empty() {
+                    // cd has to be handled by the shell itself
+                    let mut words = line.split(' ').filter(|word| !word.is_empty());
+                    if words.next() == Some("cd") {
+                        let mut path = String::from(words.next().unwrap_or("/"));
+                        path.push('\0');
+                        if chdir(path.as_str()) == -1 {
+                            println!("cd: {}: No such directory", path.trim_end_matches('\0'));
+                        }
+                        line.clear();
+                        print!("{}", LINE_START);
+                        continue;
+                    }
                     let splited: Vec<_> = line.as_str().split('|').collect();
                     let process_arguments_list: Vec<_> = splited
                         .iter()
@@ -13,8 +13,10 @@ extern crate core;
 #[macro_use]
 extern crate bitflags;
 
+use alloc::string::String;
 use alloc::vec::Vec;
 use buddy_system_allocator::LockedHeap;
+use core::convert::TryInto;
 pub use console::{flush, STDIN, STDOUT};
 pub use syscall::*;
 
@@ -82,6 +84,7 @@ bitflags! {
         const RDWR = 1 << 1;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        const APPEND = 1 << 11;
     }
 }
 
@@ -142,8 +145,22 @@ pub struct Stat {
     pub mode: StatMode,
     /// number of hard links
     pub nlink: u32,
+    /// permission bits, rwx for the owner, the group and others
+    pub perm: u32,
+    /// user id of the owner
+    pub uid: u32,
+    /// group id of the owner
+    pub gid: u32,
     /// unused pad
-    pad: [u64; 7],
+    pad0: u32,
+    /// time of last access, in seconds since the epoch
+    pub atime: u64,
+    /// time of last modification
+    pub mtime: u64,
+    /// time of last status change
+    pub ctime: u64,
+    /// unused pad
+    pad: [u64; 2],
 }
 
 impl Stat {
@@ -153,7 +170,14 @@ impl Stat {
             ino: 0,
             mode: StatMode::NULL,
             nlink: 0,
-            pad: [0; 7],
+            perm: 0,
+            uid: 0,
+            gid: 0,
+            pad0: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            pad: [0; 2],
         }
     }
 }
@@ -167,6 +191,10 @@ impl Default for Stat {
 bitflags! {
     pub struct StatMode: u32 {
         const NULL  = 0;
+        /// named pipe or pipe
+        const FIFO  = 0o010000;
+        /// character device
+        const CHR   = 0o020000;
         /// directory
         const DIR   = 0o040000;
         /// ordinary regular file
@@ -174,7 +202,64 @@ bitflags! {
     }
 }
 
+/// An entry of a directory, as read by `read_dir`
+#[derive(Debug)]
+pub struct DirEntry {
+    /// inode number
+    pub ino: u64,
+    /// name of the entry
+    pub name: String,
+    /// whether the entry is a directory
+    pub is_dir: bool,
+}
+
+/// `d_type` of directories in `linux_dirent64`
+const DT_DIR: u8 = 4;
+
+/// An iterator over the entries of a directory, "." and ".." included
+pub struct ReadDir {
+    fd: usize,
+    buf: [u8; 512],
+    /// bytes filled by the last `getdents64`
+    len: usize,
+    /// where the next record starts in `buf`
+    pos: usize,
+}
+
+impl Iterator for ReadDir {
+    type Item = DirEntry;
+    fn next(&mut self) -> Option<DirEntry> {
+        if self.pos == self.len {
+            let len = sys_getdents64(self.fd, &mut self.buf);
+            if len <= 0 {
+                return None;
+            }
+            self.len = len as usize;
+            self.pos = 0;
+        }
+        // d_ino, d_off, d_reclen and d_type, then the NUL-terminated name
+        let record = &self.buf[self.pos..self.len];
+        let ino = u64::from_le_bytes(record[0..8].try_into().unwrap());
+        let reclen = u16::from_le_bytes(record[16..18].try_into().unwrap()) as usize;
+        let name = &record[19..reclen];
+        let name_len = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+        self.pos += reclen;
+        Some(DirEntry {
+            ino,
+            name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+            is_dir: record[18] == DT_DIR,
+        })
+    }
+}
+
+impl Drop for ReadDir {
+    fn drop(&mut self) {
+        sys_close(self.fd);
+    }
+}
+
 const AT_FDCWD: isize = -100;
+const AT_REMOVEDIR: usize = 0x200;
 
 pub fn open(path: &str, flags: OpenFlags) -> isize {
     sys_openat(AT_FDCWD as usize, path, flags.bits, OpenFlags::RDWR.bits)
@@ -195,18 +280,99 @@ pub fn write(fd: usize, buf: &[u8]) -> isize {
     sys_write(fd, buf)
 }
 
+/// `whence` of `lseek` to seek from the start
+pub const SEEK_SET: usize = 0;
+/// `whence` of `lseek` to seek from the offset
+pub const SEEK_CUR: usize = 1;
+/// `whence` of `lseek` to seek from the end
+pub const SEEK_END: usize = 2;
+
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    sys_lseek(fd, offset, whence)
+}
+
+pub fn pread(fd: usize, buf: &mut [u8], offset: usize) -> isize {
+    sys_pread64(fd, buf, offset)
+}
+
+pub fn pwrite(fd: usize, buf: &[u8], offset: usize) -> isize {
+    sys_pwrite64(fd, buf, offset)
+}
+
 pub fn link(old_path: &str, new_path: &str) -> isize {
     sys_linkat(AT_FDCWD as usize, old_path, AT_FDCWD as usize, new_path, 0)
 }
 
+pub fn rename(old_path: &str, new_path: &str) -> isize {
+    sys_renameat(AT_FDCWD as usize, old_path, AT_FDCWD as usize, new_path)
+}
+
 pub fn unlink(path: &str) -> isize {
     sys_unlinkat(AT_FDCWD as usize, path, 0)
 }
 
+pub fn mkdir(path: &str) -> isize {
+    sys_mkdirat(AT_FDCWD as usize, path, 0)
+}
+
+pub fn rmdir(path: &str) -> isize {
+    sys_unlinkat(AT_FDCWD as usize, path, AT_REMOVEDIR)
+}
+
+/// Mount a new filesystem of the type `fstype`, such as "tmpfs", at the directory `target`
+pub fn mount(source: &str, target: &str, fstype: &str) -> isize {
+    sys_mount(source, target, fstype, 0)
+}
+
+pub fn umount(target: &str) -> isize {
+    sys_umount2(target, 0)
+}
+
+pub fn chdir(path: &str) -> isize {
+    sys_chdir(path)
+}
+
+pub fn getcwd(buf: &mut [u8]) -> isize {
+    sys_getcwd(buf)
+}
+
+/// Iterate over the entries of the directory at `path`
+pub fn read_dir(path: &str) -> Option<ReadDir> {
+    let fd = open(path, OpenFlags::RDONLY);
+    if fd < 0 {
+        return None;
+    }
+    Some(ReadDir {
+        fd: fd as usize,
+        buf: [0; 512],
+        len: 0,
+        pos: 0,
+    })
+}
+
 pub fn fstat(fd: usize, st: &Stat) -> isize {
     sys_fstat(fd, st)
 }
 
+/// `ioctl` request to get the number of bytes ready to be read into an `i32`
+pub const FIONREAD: usize = 0x541b;
+
+pub fn ioctl(fd: usize, request: usize, arg: usize) -> isize {
+    sys_ioctl(fd, request, arg)
+}
+
+pub fn sync() -> isize {
+    sys_sync()
+}
+
+pub fn fsync(fd: usize) -> isize {
+    sys_fsync(fd)
+}
+
+pub fn ftruncate(fd: usize, length: usize) -> isize {
+    sys_ftruncate(fd, length)
+}
+
 pub fn mail_read(buf: &mut [u8]) -> isize {
     sys_mail_read(buf)
 }